@@ -0,0 +1,261 @@
+use anyhow::{Context, Result};
+use matrix_sdk::ruma::{OwnedRoomOrAliasId, OwnedUserId};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Prefix of messages that are admin commands.
+pub const ADMIN_COMMAND_PREFIX: &str = "!bot";
+
+/// Reply to `!bot help`.
+pub const ADMIN_HELP: &str = "\
+Admin commands:
+!bot status - version, uptime, rooms and sync state
+!bot rooms - list the rooms the bot is in
+!bot stats - help requests, requesters and welcomes per room
+!bot leave <room ID or alias> - leave a room
+!bot ignore <user ID> - stop answering a user
+!bot unignore <user ID> - answer a user again
+!bot reload - reload help, command and welcome texts, bot filtering, join detection and admins";
+
+/// A command for managing the bot at runtime, sent as `!bot <command>`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AdminCommand {
+    Help,
+    Reload,
+    Status,
+    Rooms,
+    Stats,
+    Leave(OwnedRoomOrAliasId),
+    Ignore(OwnedUserId),
+    Unignore(OwnedUserId),
+}
+
+/// Parse a message as an admin command.
+///
+/// Returns `None` if the message is not meant for the bot, and the reply for a
+/// malformed command as the error.
+pub fn parse_admin_command(body: &str) -> Option<std::result::Result<AdminCommand, String>> {
+    let mut words = body.split_whitespace();
+    if words.next() != Some(ADMIN_COMMAND_PREFIX) {
+        return None;
+    }
+    let command = words.next().unwrap_or("help");
+    let argument = words.next();
+    if words.next().is_some() {
+        return Some(Err(format!(
+            "Too many arguments for '{}'. Try !bot help",
+            command
+        )));
+    }
+
+    let user_id = |usage: &str| match argument {
+        None => Err(format!("Usage: !bot {} <user ID>", usage)),
+        Some(user_id) => OwnedUserId::try_from(user_id)
+            .map_err(|_| format!("'{}' is not a valid user ID", user_id)),
+    };
+    let result = match (command, argument) {
+        ("help", None) => Ok(AdminCommand::Help),
+        ("reload", None) => Ok(AdminCommand::Reload),
+        ("status", None) => Ok(AdminCommand::Status),
+        ("rooms", None) => Ok(AdminCommand::Rooms),
+        ("stats", None) => Ok(AdminCommand::Stats),
+        ("leave", None) => Err("Usage: !bot leave <room ID or alias>".to_string()),
+        ("leave", Some(room)) => OwnedRoomOrAliasId::try_from(room)
+            .map(AdminCommand::Leave)
+            .map_err(|_| format!("'{}' is not a valid room ID or alias", room)),
+        ("ignore", _) => user_id("ignore").map(AdminCommand::Ignore),
+        ("unignore", _) => user_id("unignore").map(AdminCommand::Unignore),
+        ("help" | "reload" | "status" | "rooms" | "stats", Some(_)) => {
+            Err(format!("'{}' takes no arguments", command))
+        }
+        _ => Err(format!("Unknown command '{}'. Try !bot help", command)),
+    };
+    Some(result)
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct IgnoreFile {
+    ignored_users: BTreeSet<String>,
+}
+
+/// Users ignored with `!bot ignore`, kept in a JSON file across restarts.
+///
+/// They are ignored in addition to `bot_filtering.ignored_users`.
+#[derive(Debug)]
+pub struct IgnoreList {
+    path: PathBuf,
+    users: BTreeSet<String>,
+}
+
+impl IgnoreList {
+    /// Load the list from `path`, which may not exist yet.
+    pub fn load(path: &Path) -> Result<Self> {
+        let users = match fs::read_to_string(path) {
+            Ok(content) => {
+                serde_json::from_str::<IgnoreFile>(&content)
+                    .with_context(|| format!("Failed to parse ignore file '{}'", path.display()))?
+                    .ignored_users
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => BTreeSet::new(),
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("Failed to read ignore file '{}'", path.display()));
+            }
+        };
+        Ok(Self {
+            path: path.to_path_buf(),
+            users,
+        })
+    }
+
+    pub fn contains(&self, user_id: &str) -> bool {
+        self.users.contains(user_id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.users.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty()
+    }
+
+    /// Ignore a user, returning `false` if they already were.
+    pub fn insert(&mut self, user_id: &str) -> Result<bool> {
+        if !self.users.insert(user_id.to_string()) {
+            return Ok(false);
+        }
+        self.save()?;
+        Ok(true)
+    }
+
+    /// Stop ignoring a user, returning `false` if they weren't.
+    pub fn remove(&mut self, user_id: &str) -> Result<bool> {
+        if !self.users.remove(user_id) {
+            return Ok(false);
+        }
+        self.save()?;
+        Ok(true)
+    }
+
+    /// Write the list to a temporary file and rename it, so it is never left half written.
+    fn save(&self) -> Result<()> {
+        let content = serde_json::to_string_pretty(&IgnoreFile {
+            ignored_users: self.users.clone(),
+        })
+        .context("Failed to serialize ignore list")?;
+        let tmp_path = self.path.with_extension("tmp");
+        fs::write(&tmp_path, content)
+            .with_context(|| format!("Failed to write ignore file '{}'", tmp_path.display()))?;
+        fs::rename(&tmp_path, &self.path)
+            .with_context(|| format!("Failed to save ignore file '{}'", self.path.display()))
+    }
+}
+
+/// Format an uptime like `2d 3h 4m`, or in seconds if it is shorter than a minute.
+pub fn format_uptime(uptime: Duration) -> String {
+    let seconds = uptime.as_secs();
+    if seconds < 60 {
+        return format!("{}s", seconds);
+    }
+    let (days, hours, minutes) = (seconds / 86400, seconds / 3600 % 24, seconds / 60 % 60);
+    if days > 0 {
+        format!("{}d {}h {}m", days, hours, minutes)
+    } else if hours > 0 {
+        format!("{}h {}m", hours, minutes)
+    } else {
+        format!("{}m", minutes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use matrix_sdk::ruma::owned_user_id;
+
+    #[test]
+    fn test_parse_admin_command() {
+        // Given admin commands, malformed ones and ordinary messages
+        // When parsing them
+        // Then commands should be recognized and mistakes explained
+        assert_eq!(parse_admin_command("!help"), None);
+        assert_eq!(parse_admin_command("!botany is fun"), None);
+        assert_eq!(parse_admin_command("!bot"), Some(Ok(AdminCommand::Help)));
+        assert_eq!(
+            parse_admin_command("  !bot   status "),
+            Some(Ok(AdminCommand::Status))
+        );
+        assert_eq!(
+            parse_admin_command("!bot stats"),
+            Some(Ok(AdminCommand::Stats))
+        );
+        assert_eq!(
+            parse_admin_command("!bot ignore @spam:example.com"),
+            Some(Ok(AdminCommand::Ignore(owned_user_id!(
+                "@spam:example.com"
+            ))))
+        );
+        assert!(matches!(
+            parse_admin_command("!bot leave #support:example.com"),
+            Some(Ok(AdminCommand::Leave(room))) if room.is_room_alias_id()
+        ));
+        assert_eq!(
+            parse_admin_command("!bot ignore spam"),
+            Some(Err("'spam' is not a valid user ID".to_string()))
+        );
+        assert_eq!(
+            parse_admin_command("!bot unignore"),
+            Some(Err("Usage: !bot unignore <user ID>".to_string()))
+        );
+        assert_eq!(
+            parse_admin_command("!bot status now"),
+            Some(Err("'status' takes no arguments".to_string()))
+        );
+        assert_eq!(
+            parse_admin_command("!bot dance"),
+            Some(Err("Unknown command 'dance'. Try !bot help".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_ignore_list_is_persisted() {
+        // Given an empty ignore list in a file that doesn't exist yet
+        let path = Path::new("test_ignore_list.json");
+        let _ = fs::remove_file(path);
+        let mut list = IgnoreList::load(path).unwrap();
+
+        // When ignoring two users, one twice, and unignoring one
+        assert!(list.insert("@spam:example.com").unwrap());
+        assert!(!list.insert("@spam:example.com").unwrap());
+        assert!(list.insert("@noise:example.com").unwrap());
+        assert!(list.remove("@noise:example.com").unwrap());
+        assert!(!list.remove("@noise:example.com").unwrap());
+
+        // Then loading the file again should have the remaining user
+        let reloaded = IgnoreList::load(path).unwrap();
+        assert!(reloaded.contains("@spam:example.com"));
+        assert!(!reloaded.contains("@noise:example.com"));
+        assert_eq!(reloaded.len(), 1);
+
+        // Clean up
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_format_uptime() {
+        // Given uptimes of seconds, minutes, hours and days
+        // When formatting them
+        // Then only the significant units should be shown
+        assert_eq!(format_uptime(Duration::from_secs(42)), "42s");
+        assert_eq!(format_uptime(Duration::from_secs(5 * 60 + 7)), "5m");
+        assert_eq!(format_uptime(Duration::from_secs(3 * 3600 + 60)), "3h 1m");
+        assert_eq!(
+            format_uptime(Duration::from_secs(2 * 86400 + 3 * 3600 + 4 * 60)),
+            "2d 3h 4m"
+        );
+    }
+}
@@ -0,0 +1,171 @@
+use crate::{Config, HelpFormat, load_help_text, load_welcome_text, render_message};
+use anyhow::Result;
+use matrix_sdk::ruma::{RoomId, UserId};
+
+/// Check the parts of a config that can be validated without a homeserver.
+///
+/// Returns a description of every problem found rather than stopping at the
+/// first one, so that they can all be fixed in one go.
+pub fn check_config(config: &Config) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    check_user_id(&mut problems, "username", &config.username);
+    for admin in &config.admins {
+        check_user_id(&mut problems, "admins", admin);
+    }
+    if let Some(ref admin_room) = config.admin_room {
+        check_room_id(&mut problems, "admin_room", admin_room);
+    }
+    for user in &config.bot_filtering.ignored_users {
+        check_user_id(&mut problems, "bot_filtering.ignored_users", user);
+    }
+    for room in &config.join_detection.monitored_rooms {
+        check_room_id(&mut problems, "join_detection.monitored_rooms", room);
+    }
+    for room in &config.faq.rooms {
+        check_room_id(&mut problems, "faq.rooms", room);
+    }
+    for room in &config.housekeeping.exempt_rooms {
+        check_room_id(&mut problems, "housekeeping.exempt_rooms", room);
+    }
+
+    check_message(
+        &mut problems,
+        "help_file",
+        load_help_text(&config.help_file),
+        &config.help_format,
+    );
+    for command in &config.commands {
+        check_message(
+            &mut problems,
+            &format!("commands.{}", command.name),
+            command.load_response(),
+            &command.format,
+        );
+    }
+    if let Some(ref welcome_file) = config.join_detection.welcome_file {
+        check_message(
+            &mut problems,
+            "join_detection.welcome_file",
+            load_welcome_text(welcome_file),
+            &config.join_detection.welcome_format,
+        );
+    }
+
+    problems
+}
+
+fn check_user_id(problems: &mut Vec<String>, key: &str, user_id: &str) {
+    if let Err(e) = UserId::parse(user_id) {
+        problems.push(format!("Invalid user ID '{}' in '{}': {}", user_id, key, e));
+    }
+}
+
+fn check_room_id(problems: &mut Vec<String>, key: &str, room_id: &str) {
+    if let Err(e) = RoomId::parse(room_id) {
+        problems.push(format!("Invalid room ID '{}' in '{}': {}", room_id, key, e));
+    }
+}
+
+/// Check that a message file could be read and renders to a non-empty message.
+fn check_message(problems: &mut Vec<String>, key: &str, text: Result<String>, format: &HelpFormat) {
+    let text = match text {
+        Ok(text) => text,
+        Err(e) => {
+            problems.push(format!("{:#}", e));
+            return;
+        }
+    };
+    if render_message(&text, format).body().trim().is_empty() {
+        problems.push(format!("'{}' renders to an empty message", key));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use indoc::indoc;
+    use std::fs;
+
+    fn config(extra: &str, help_file: &str) -> Config {
+        let toml_str = format!(
+            "{}help_file = \"{}\"\n{}",
+            indoc! {"
+                homeserver = \"https://matrix.example.com\"
+                username = \"@bot:example.com\"
+                access_token = \"secret_token\"
+                admins = [\"@admin:example.com\"]
+            "},
+            help_file,
+            extra
+        );
+        Config::from_toml(&toml_str).unwrap()
+    }
+
+    #[test]
+    fn test_check_config_valid() {
+        // Given a valid config with an existing help file
+        fs::write("test_check_valid_help.md", "# Help\n\nType `!help`.").unwrap();
+        let config = config(
+            indoc! {"
+                admin_room = \"!admins:example.com\"
+
+                [join_detection]
+                monitored_rooms = [\"!room:example.com\"]
+            "},
+            "test_check_valid_help.md",
+        );
+
+        // When checking it
+        let problems = check_config(&config);
+
+        // Then no problems should be found
+        assert!(problems.is_empty(), "{:?}", problems);
+
+        // Clean up
+        fs::remove_file("test_check_valid_help.md").unwrap();
+    }
+
+    #[test]
+    fn test_check_config_reports_all_problems() {
+        // Given a config with invalid IDs, missing help and command files and an empty welcome file
+        fs::write("test_check_empty_welcome.md", "\n").unwrap();
+        let config = config(
+            indoc! {"
+                [bot_filtering]
+                ignored_users = [\"spam-bot\"]
+
+                [join_detection]
+                monitored_rooms = [\"#alias:example.com\"]
+                welcome_file = \"test_check_empty_welcome.md\"
+
+                [[commands]]
+                name = \"rules\"
+                file = \"test_check_missing_rules.md\"
+            "},
+            "test_check_missing_help.md",
+        );
+
+        // When checking it
+        let problems = check_config(&config);
+
+        // Then every problem should be reported
+        assert_eq!(problems.len(), 5, "{:?}", problems);
+        assert!(
+            problems[0].contains("Invalid user ID 'spam-bot' in 'bot_filtering.ignored_users'")
+        );
+        assert!(
+            problems[1].contains(
+                "Invalid room ID '#alias:example.com' in 'join_detection.monitored_rooms'"
+            )
+        );
+        assert!(problems[2].contains("Failed to read help file 'test_check_missing_help.md'"));
+        assert!(problems[3].contains(
+            "Failed to read response file 'test_check_missing_rules.md' of command 'rules'"
+        ));
+        assert!(problems[4].contains("'join_detection.welcome_file' renders to an empty message"));
+
+        // Clean up
+        fs::remove_file("test_check_empty_welcome.md").unwrap();
+    }
+}
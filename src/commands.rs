@@ -0,0 +1,324 @@
+use crate::HelpFormat;
+use anyhow::{Context, Result, anyhow};
+use serde::{Deserialize, Deserializer};
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::str::FromStr;
+
+/// Names of built-in commands, which custom commands can't use.
+const RESERVED_NAMES: [&str; 2] = ["help", "bot"];
+
+/// Where the response to a command is sent.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum Delivery {
+    /// To the room the command was sent in
+    #[default]
+    Room,
+    /// To the room, as a reply to the command
+    Reply,
+    /// To a direct message room with the sender, created if needed
+    Direct,
+}
+
+impl FromStr for Delivery {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "room" => Ok(Delivery::Room),
+            "reply" => Ok(Delivery::Reply),
+            "direct" | "dm" => Ok(Delivery::Direct),
+            _ => Err(anyhow!(
+                "Invalid delivery '{}'. Valid options are: room, reply, direct",
+                s
+            )),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Delivery {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        Delivery::from_str(&s).map_err(serde::de::Error::custom)
+    }
+}
+
+impl fmt::Display for Delivery {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Delivery::Room => write!(f, "room"),
+            Delivery::Reply => write!(f, "reply"),
+            Delivery::Direct => write!(f, "direct"),
+        }
+    }
+}
+
+/// A command with a static response, configured as `[[commands]]`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CommandConfig {
+    /// Name the command is sent as, without the `!`
+    pub name: String,
+    /// Other names for the same command
+    #[serde(default)]
+    pub aliases: Vec<String>,
+    /// Short description listed by `!help`
+    pub description: Option<String>,
+    /// File containing the response
+    pub file: Option<String>,
+    /// Response given inline instead of in a file
+    pub text: Option<String>,
+    /// Format of the response (plain, html, markdown)
+    #[serde(default)]
+    pub format: HelpFormat,
+    /// Where the response is sent (room, reply, direct)
+    #[serde(default)]
+    pub delivery: Delivery,
+}
+
+impl CommandConfig {
+    /// Read the response from `file`, or take it from `text`.
+    pub fn load_response(&self) -> Result<String> {
+        match (&self.file, &self.text) {
+            (Some(file), _) => fs::read_to_string(file).with_context(|| {
+                format!(
+                    "Failed to read response file '{}' of command '{}'",
+                    file, self.name
+                )
+            }),
+            (None, Some(text)) => Ok(text.clone()),
+            (None, None) => Err(anyhow!("Command '{}' has no response", self.name)),
+        }
+    }
+
+    fn names(&self) -> impl Iterator<Item = &String> {
+        std::iter::once(&self.name).chain(&self.aliases)
+    }
+}
+
+/// Check that command names are usable and unique, and that each command has one response.
+pub fn validate_commands(commands: &[CommandConfig]) -> Result<()> {
+    let mut seen = HashSet::new();
+    for command in commands {
+        for name in command.names() {
+            if name.is_empty() || name.starts_with('!') || name.contains(char::is_whitespace) {
+                return Err(anyhow!(
+                    "Invalid command name '{}', expected a single word without '!'",
+                    name
+                ));
+            }
+            if RESERVED_NAMES.contains(&name.as_str()) {
+                return Err(anyhow!(
+                    "Command name '{}' is reserved for a built-in command",
+                    name
+                ));
+            }
+            if !seen.insert(name.as_str()) {
+                return Err(anyhow!("Command name '{}' is used more than once", name));
+            }
+        }
+        match (&command.file, &command.text) {
+            (Some(_), Some(_)) => {
+                return Err(anyhow!(
+                    "Command '{}' has both 'file' and 'text', only one can be used",
+                    command.name
+                ));
+            }
+            (None, None) => {
+                return Err(anyhow!(
+                    "Command '{}' needs either 'file' or 'text'",
+                    command.name
+                ));
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// A custom command with its response loaded.
+#[derive(Debug, Clone)]
+pub struct CustomCommand {
+    pub config: CommandConfig,
+    pub response: String,
+}
+
+impl CustomCommand {
+    pub fn load(config: &CommandConfig) -> Result<Self> {
+        Ok(Self {
+            config: config.clone(),
+            response: config.load_response()?,
+        })
+    }
+}
+
+/// Whether a message asks for the help text, with `!help` as its whole first word.
+pub fn is_help_command(body: &str) -> bool {
+    body.split_whitespace().next() == Some("!help")
+}
+
+/// Find the command a message invokes by its first word, e.g. `!rules` or `!rules please`.
+pub fn find_command<'a>(commands: &'a [CustomCommand], body: &str) -> Option<&'a CustomCommand> {
+    let name = body.split_whitespace().next()?.strip_prefix('!')?;
+    commands
+        .iter()
+        .find(|command| command.config.names().any(|other| other == name))
+}
+
+/// The help text followed by a list of the custom commands, in the help text's format.
+pub fn help_with_commands<'a>(
+    help_text: &str,
+    format: &HelpFormat,
+    commands: impl IntoIterator<Item = &'a CommandConfig>,
+) -> String {
+    let mut commands = commands.into_iter().peekable();
+    if commands.peek().is_none() {
+        return help_text.to_string();
+    }
+    let mut text = help_text.trim_end().to_string();
+    text.push_str(match format {
+        HelpFormat::Plain => "\n\nMore commands:",
+        HelpFormat::Markdown => "\n\nMore commands:\n",
+        HelpFormat::Html => "\n<p>More commands:</p>\n<ul>",
+    });
+    for command in commands {
+        let names: Vec<String> = command
+            .names()
+            .map(|name| match format {
+                HelpFormat::Plain => format!("!{}", name),
+                HelpFormat::Markdown => format!("`!{}`", name),
+                HelpFormat::Html => format!("<code>!{}</code>", name),
+            })
+            .collect();
+        let description = command
+            .description
+            .as_ref()
+            .map(|description| format!(" - {}", description))
+            .unwrap_or_default();
+        let line = format!("{}{}", names.join(", "), description);
+        text.push_str(&match format {
+            HelpFormat::Plain => format!("\n{}", line),
+            HelpFormat::Markdown => format!("\n- {}", line),
+            HelpFormat::Html => format!("<li>{}</li>", line),
+        });
+    }
+    if *format == HelpFormat::Html {
+        text.push_str("</ul>");
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(name: &str, aliases: &[&str], text: &str) -> CommandConfig {
+        CommandConfig {
+            name: name.to_string(),
+            aliases: aliases.iter().map(|alias| alias.to_string()).collect(),
+            description: None,
+            file: None,
+            text: Some(text.to_string()),
+            format: HelpFormat::Plain,
+            delivery: Delivery::Room,
+        }
+    }
+
+    #[test]
+    fn test_validate_commands() {
+        // Given valid commands, and ones with clashing, reserved or malformed names or responses
+        let valid = [
+            command("rules", &["r"], "Be nice"),
+            command("faq", &[], "FAQ"),
+        ];
+        let clashing = [command("rules", &[], "A"), command("faq", &["rules"], "B")];
+        let reserved = [command("help", &[], "A")];
+        let malformed = [command("!rules", &[], "A")];
+        let mut both = command("rules", &[], "A");
+        both.file = Some("rules.md".to_string());
+
+        // When validating them
+        // Then only the valid ones should be accepted, and the problems explained
+        assert!(validate_commands(&valid).is_ok());
+        let error =
+            |commands: &[CommandConfig]| validate_commands(commands).unwrap_err().to_string();
+        assert_eq!(
+            error(&clashing),
+            "Command name 'rules' is used more than once"
+        );
+        assert_eq!(
+            error(&reserved),
+            "Command name 'help' is reserved for a built-in command"
+        );
+        assert_eq!(
+            error(&malformed),
+            "Invalid command name '!rules', expected a single word without '!'"
+        );
+        assert_eq!(
+            error(&[both]),
+            "Command 'rules' has both 'file' and 'text', only one can be used"
+        );
+    }
+
+    #[test]
+    fn test_find_command() {
+        // Given a command with an alias
+        let commands = [CustomCommand::load(&command("rules", &["r"], "Be nice")).unwrap()];
+
+        // When looking up messages
+        let find = |body| find_command(&commands, body).map(|command| command.response.as_str());
+
+        // Then the name or alias must be the whole first word
+        assert_eq!(find("!rules"), Some("Be nice"));
+        assert_eq!(find("!r please"), Some("Be nice"));
+        assert_eq!(find("!rulesplease"), None);
+        assert_eq!(find("rules"), None);
+        assert_eq!(find(""), None);
+    }
+
+    #[test]
+    fn test_help_command_is_a_whole_word() {
+        // Given a custom command whose name starts with "help"
+        let commands = [CustomCommand::load(&command("helpdesk", &[], "Open a ticket")).unwrap()];
+
+        // When checking messages for the help command and custom commands
+        let find = |body| find_command(&commands, body).map(|command| command.response.as_str());
+
+        // Then `!help` must be the whole first word, leaving `!helpdesk` to the command
+        assert!(is_help_command("!help"));
+        assert!(is_help_command("!help please"));
+        assert!(!is_help_command("!helpdesk"));
+        assert!(!is_help_command("help"));
+        assert_eq!(find("!helpdesk"), Some("Open a ticket"));
+    }
+
+    #[test]
+    fn test_help_with_commands() {
+        // Given two commands, one with an alias and a description
+        let mut rules = command("rules", &["r"], "Be nice");
+        rules.description = Some("Room rules".to_string());
+        let commands = [rules, command("faq", &[], "FAQ")];
+
+        // When adding them to help texts in each format
+        let plain = help_with_commands("Help\n", &HelpFormat::Plain, &commands);
+        let markdown = help_with_commands("# Help", &HelpFormat::Markdown, &commands);
+        let html = help_with_commands("<p>Help</p>", &HelpFormat::Html, &commands);
+        let none = help_with_commands("Help\n", &HelpFormat::Plain, &[]);
+
+        // Then they should be listed in the same format, and the text kept without commands
+        assert_eq!(
+            plain,
+            "Help\n\nMore commands:\n!rules, !r - Room rules\n!faq"
+        );
+        assert_eq!(
+            markdown,
+            "# Help\n\nMore commands:\n\n- `!rules`, `!r` - Room rules\n- `!faq`"
+        );
+        assert_eq!(
+            html,
+            "<p>Help</p>\n<p>More commands:</p>\n<ul><li><code>!rules</code>, <code>!r</code> - Room rules</li><li><code>!faq</code></li></ul>"
+        );
+        assert_eq!(none, "Help\n");
+    }
+}
@@ -0,0 +1,339 @@
+use crate::commands::CommandConfig;
+use anyhow::{Context, Result, anyhow};
+use regex::Regex;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+/// Configuration for answering frequently asked questions without a command.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FaqConfig {
+    /// Whether to answer frequently asked questions at all
+    pub enabled: bool,
+    /// Room IDs to answer in (empty = all rooms)
+    pub rooms: Vec<String>,
+    /// Seconds before the same question is answered again in a room
+    pub cooldown_seconds: u64,
+    /// Share of an entry's keywords a message must contain, above 0 and at most 1
+    pub threshold: f64,
+    pub entries: Vec<FaqEntry>,
+}
+
+impl Default for FaqConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            rooms: Vec::new(),
+            cooldown_seconds: 600,
+            threshold: 0.75,
+            entries: Vec::new(),
+        }
+    }
+}
+
+/// A frequently asked question, recognized by keywords or patterns.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FaqEntry {
+    /// Name for logs and metrics
+    pub name: String,
+    /// Words or phrases, matched case-insensitively as whole words
+    #[serde(default)]
+    pub keywords: Vec<String>,
+    /// Regular expressions, any of which matches on its own
+    #[serde(default)]
+    pub patterns: Vec<String>,
+    /// Topic to answer with: `help` or the name of a custom command
+    pub topic: String,
+}
+
+/// Check the FAQ entries, whose topics must be `help` or one of `commands`.
+pub fn validate_faq(config: &FaqConfig, commands: &[CommandConfig]) -> Result<()> {
+    if !(config.threshold > 0.0 && config.threshold <= 1.0) {
+        return Err(anyhow!(
+            "'faq.threshold' must be greater than 0 and at most 1"
+        ));
+    }
+    let mut names = HashSet::new();
+    for entry in &config.entries {
+        if !names.insert(entry.name.as_str()) {
+            return Err(anyhow!("FAQ name '{}' is used more than once", entry.name));
+        }
+        if entry
+            .keywords
+            .iter()
+            .all(|keyword| words(keyword).is_empty())
+            && entry.patterns.is_empty()
+        {
+            return Err(anyhow!(
+                "FAQ '{}' needs 'keywords' or 'patterns'",
+                entry.name
+            ));
+        }
+        for pattern in &entry.patterns {
+            Regex::new(pattern).with_context(|| {
+                format!("Invalid pattern '{}' of FAQ '{}'", pattern, entry.name)
+            })?;
+        }
+        if entry.topic != "help" && !commands.iter().any(|command| command.name == entry.topic) {
+            return Err(anyhow!(
+                "FAQ '{}' links to unknown topic '{}', expected 'help' or a command name",
+                entry.name,
+                entry.topic
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Split text into lowercase words, ignoring punctuation.
+fn words(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+#[derive(Debug)]
+struct CompiledEntry {
+    entry: FaqEntry,
+    /// Each keyword as its words, which must appear next to each other
+    keywords: Vec<Vec<String>>,
+    patterns: Vec<Regex>,
+}
+
+impl CompiledEntry {
+    /// How well a message matches, from 0 to 1.
+    fn score(&self, body: &str, message_words: &[String]) -> f64 {
+        if self.patterns.iter().any(|pattern| pattern.is_match(body)) {
+            return 1.0;
+        }
+        if self.keywords.is_empty() {
+            return 0.0;
+        }
+        let found = self
+            .keywords
+            .iter()
+            .filter(|keyword| {
+                message_words
+                    .windows(keyword.len())
+                    .any(|window| window == keyword.as_slice())
+            })
+            .count();
+        found as f64 / self.keywords.len() as f64
+    }
+}
+
+/// Finds the FAQ entry a message asks about.
+#[derive(Debug)]
+pub struct FaqMatcher {
+    entries: Vec<CompiledEntry>,
+    rooms: Vec<String>,
+    threshold: f64,
+    pub cooldown: Duration,
+}
+
+impl FaqMatcher {
+    pub fn new(config: &FaqConfig) -> Result<Self> {
+        let entries = config
+            .entries
+            .iter()
+            .map(|entry| {
+                let patterns = entry
+                    .patterns
+                    .iter()
+                    .map(|pattern| Regex::new(pattern))
+                    .collect::<std::result::Result<Vec<_>, _>>()
+                    .with_context(|| format!("Invalid pattern of FAQ '{}'", entry.name))?;
+                Ok(CompiledEntry {
+                    entry: entry.clone(),
+                    keywords: entry
+                        .keywords
+                        .iter()
+                        .map(|keyword| words(keyword))
+                        .filter(|keyword| !keyword.is_empty())
+                        .collect(),
+                    patterns,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            entries,
+            rooms: config.rooms.clone(),
+            threshold: config.threshold,
+            cooldown: Duration::from_secs(config.cooldown_seconds),
+        })
+    }
+
+    /// Whether questions are answered in a room.
+    pub fn answers_in(&self, room_id: &str) -> bool {
+        self.rooms.is_empty() || self.rooms.iter().any(|room| room == room_id)
+    }
+
+    /// The entry that matches a message best, if any reaches the threshold.
+    pub fn find(&self, body: &str) -> Option<&FaqEntry> {
+        let message_words = words(body);
+        let mut best: Option<(&CompiledEntry, f64)> = None;
+        for entry in &self.entries {
+            let score = entry.score(body, &message_words);
+            if score >= self.threshold && best.is_none_or(|(_, best_score)| score > best_score) {
+                best = Some((entry, score));
+            }
+        }
+        best.map(|(compiled, _)| &compiled.entry)
+    }
+}
+
+/// When each question was last answered in each room.
+#[derive(Debug, Default)]
+pub struct FaqCooldowns {
+    answered: HashMap<(String, String), Instant>,
+}
+
+impl FaqCooldowns {
+    /// Record an answer unless the same question was answered in the room within `cooldown`.
+    ///
+    /// Recorded before sending, so that a question asked twice in a row is answered once;
+    /// [`FaqCooldowns::forget`] takes it back if the answer couldn't be sent.
+    pub fn try_answer(
+        &mut self,
+        room_id: &str,
+        name: &str,
+        cooldown: Duration,
+        now: Instant,
+    ) -> bool {
+        self.answered
+            .retain(|_, answered| now.saturating_duration_since(*answered) < cooldown);
+        let key = (room_id.to_string(), name.to_string());
+        if self.answered.contains_key(&key) {
+            return false;
+        }
+        self.answered.insert(key, now);
+        true
+    }
+
+    /// Forget an answer that couldn't be sent, so the question is answered when asked again.
+    pub fn forget(&mut self, room_id: &str, name: &str) {
+        self.answered
+            .remove(&(room_id.to_string(), name.to_string()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Delivery, HelpFormat};
+
+    fn entry(name: &str, keywords: &[&str], patterns: &[&str], topic: &str) -> FaqEntry {
+        FaqEntry {
+            name: name.to_string(),
+            keywords: keywords.iter().map(|keyword| keyword.to_string()).collect(),
+            patterns: patterns.iter().map(|pattern| pattern.to_string()).collect(),
+            topic: topic.to_string(),
+        }
+    }
+
+    fn config(entries: Vec<FaqEntry>) -> FaqConfig {
+        FaqConfig {
+            enabled: true,
+            entries,
+            ..FaqConfig::default()
+        }
+    }
+
+    #[test]
+    fn test_faq_matching() {
+        // Given a keyword entry needing three of four keywords, and a pattern entry
+        let matcher = FaqMatcher::new(&config(vec![
+            entry(
+                "password",
+                &["reset", "password", "forgot", "log in"],
+                &[],
+                "help",
+            ),
+            entry("vpn", &[], &["(?i)\\bvpn\\b.*(down|broken)"], "help"),
+        ]))
+        .unwrap();
+
+        // When matching messages
+        let find = |body| matcher.find(body).map(|entry| entry.name.as_str());
+
+        // Then only messages reaching the threshold or matching a pattern should be answered
+        assert_eq!(
+            find("I forgot my PASSWORD, how do I reset it?"),
+            Some("password")
+        );
+        assert_eq!(find("Can't log in, forgot my password"), Some("password"));
+        assert_eq!(find("How do I reset my password?"), None);
+        assert_eq!(find("The passwords login page"), None);
+        assert_eq!(find("Is the VPN down again?"), Some("vpn"));
+        assert_eq!(find("vpnd is down"), None);
+    }
+
+    #[test]
+    fn test_faq_cooldown() {
+        // Given an empty cooldown record
+        let mut cooldowns = FaqCooldowns::default();
+        let cooldown = Duration::from_secs(600);
+        let now = Instant::now();
+
+        // When answering the same question twice, in another room, after the cooldown,
+        // and again after an answer that couldn't be sent
+        let first = cooldowns.try_answer("!a:example.com", "vpn", cooldown, now);
+        let again = cooldowns.try_answer("!a:example.com", "vpn", cooldown, now);
+        let other_room = cooldowns.try_answer("!b:example.com", "vpn", cooldown, now);
+        let later = cooldowns.try_answer("!a:example.com", "vpn", cooldown, now + cooldown);
+        cooldowns.forget("!b:example.com", "vpn");
+        let after_failure = cooldowns.try_answer("!b:example.com", "vpn", cooldown, now);
+
+        // Then only the repeat within the cooldown in the same room should be skipped
+        assert!(first);
+        assert!(!again);
+        assert!(other_room);
+        assert!(later);
+        assert!(after_failure);
+    }
+
+    #[test]
+    fn test_validate_faq() {
+        // Given a command, and FAQ entries with an unknown topic, a bad pattern or no keywords
+        let commands = [CommandConfig {
+            name: "rules".to_string(),
+            aliases: Vec::new(),
+            description: None,
+            file: None,
+            text: Some("Be nice".to_string()),
+            format: HelpFormat::Plain,
+            delivery: Delivery::Room,
+        }];
+        let valid = config(vec![entry("rules", &["rules"], &[], "rules")]);
+        let unknown_topic = config(vec![entry("faq", &["faq"], &[], "faq")]);
+        let bad_pattern = config(vec![entry("vpn", &[], &["(vpn"], "help")]);
+        let no_keywords = config(vec![entry("empty", &["?"], &[], "help")]);
+        let zero_threshold = FaqConfig {
+            threshold: 0.0,
+            ..valid.clone()
+        };
+
+        // When validating them
+        let error =
+            |config: &FaqConfig| format!("{:#}", validate_faq(config, &commands).unwrap_err());
+
+        // Then only the valid entries should be accepted
+        assert!(validate_faq(&valid, &commands).is_ok());
+        assert_eq!(
+            error(&unknown_topic),
+            "FAQ 'faq' links to unknown topic 'faq', expected 'help' or a command name"
+        );
+        assert!(error(&bad_pattern).starts_with("Invalid pattern '(vpn' of FAQ 'vpn'"));
+        assert_eq!(
+            error(&no_keywords),
+            "FAQ 'empty' needs 'keywords' or 'patterns'"
+        );
+        assert_eq!(
+            error(&zero_threshold),
+            "'faq.threshold' must be greater than 0 and at most 1"
+        );
+    }
+}
@@ -0,0 +1,169 @@
+use serde::Deserialize;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Configuration for the `/healthz` and `/readyz` endpoints.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthConfig {
+    /// Whether to serve the health endpoints at all
+    pub enabled: bool,
+    /// Address and port to listen on, may be the same as the metrics one
+    pub listen_address: String,
+    /// The bot is not ready if its last successful sync is older than this
+    pub max_sync_age_seconds: u64,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            listen_address: "127.0.0.1:9185".to_string(),
+            max_sync_age_seconds: 120,
+        }
+    }
+}
+
+/// Whether the bot is able to answer, and why not.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Readiness {
+    Ready,
+    NotReady(String),
+}
+
+#[derive(Debug, Default)]
+struct State {
+    logged_in: bool,
+    last_sync: Option<Instant>,
+    shutting_down: bool,
+}
+
+/// Tracks login and sync progress for the readiness check.
+///
+/// Cloning is cheap, and all clones share the same state.
+#[derive(Debug, Clone)]
+pub struct Health {
+    state: Arc<Mutex<State>>,
+    max_sync_age: Duration,
+}
+
+impl Health {
+    pub fn new(config: &HealthConfig) -> Self {
+        Self {
+            state: Arc::default(),
+            max_sync_age: Duration::from_secs(config.max_sync_age_seconds),
+        }
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().expect("Health lock poisoned")
+    }
+
+    /// Forget about the previous login, e.g. before logging in again.
+    pub fn reset(&self) {
+        *self.state() = State::default();
+    }
+
+    pub fn logged_in(&self) {
+        self.state().logged_in = true;
+    }
+
+    /// Record a successful sync, the first of which completes startup.
+    pub fn synced(&self) {
+        self.state().last_sync = Some(Instant::now());
+    }
+
+    /// Whether a sync succeeded since the last reset.
+    pub fn has_synced(&self) -> bool {
+        self.state().last_sync.is_some()
+    }
+
+    pub fn shutting_down(&self) {
+        self.state().shutting_down = true;
+    }
+
+    pub fn readiness(&self) -> Readiness {
+        self.readiness_at(Instant::now())
+    }
+
+    fn readiness_at(&self, now: Instant) -> Readiness {
+        let state = self.state();
+        if state.shutting_down {
+            return Readiness::NotReady("shutting down".to_string());
+        }
+        if !state.logged_in {
+            return Readiness::NotReady("not logged in".to_string());
+        }
+        let Some(last_sync) = state.last_sync else {
+            return Readiness::NotReady("initial sync not completed".to_string());
+        };
+        let age = now.saturating_duration_since(last_sync);
+        if age > self.max_sync_age {
+            return Readiness::NotReady(format!(
+                "last successful sync was {} seconds ago",
+                age.as_secs()
+            ));
+        }
+        Readiness::Ready
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_readiness_follows_login_and_sync() {
+        // Given a fresh health tracker allowing syncs up to two minutes old
+        let health = Health::new(&HealthConfig::default());
+        let not_logged_in = health.readiness();
+
+        // When logging in, syncing, and time passing
+        health.logged_in();
+        let not_synced = health.readiness();
+        health.synced();
+        let now = Instant::now();
+        let ready = health.readiness_at(now);
+        let stale = health.readiness_at(now + Duration::from_secs(300));
+        health.shutting_down();
+        let shutting_down = health.readiness_at(now);
+
+        // Then the bot should only be ready between a recent sync and the shutdown
+        assert_eq!(
+            not_logged_in,
+            Readiness::NotReady("not logged in".to_string())
+        );
+        assert_eq!(
+            not_synced,
+            Readiness::NotReady("initial sync not completed".to_string())
+        );
+        assert_eq!(ready, Readiness::Ready);
+        assert!(
+            matches!(stale, Readiness::NotReady(reason) if reason.starts_with("last successful sync was"))
+        );
+        assert_eq!(
+            shutting_down,
+            Readiness::NotReady("shutting down".to_string())
+        );
+    }
+
+    #[test]
+    fn test_reset_forgets_login() {
+        // Given a ready bot
+        let health = Health::new(&HealthConfig::default());
+        health.logged_in();
+        health.synced();
+
+        // When resetting before logging in again
+        let synced_before = health.has_synced();
+        health.reset();
+
+        // Then it should no longer be ready, nor count the earlier sync
+        assert_eq!(
+            health.readiness(),
+            Readiness::NotReady("not logged in".to_string())
+        );
+        assert!(synced_before);
+        assert!(!health.has_synced());
+    }
+}
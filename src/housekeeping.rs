@@ -0,0 +1,336 @@
+use crate::HousekeepingConfig;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Why the housekeeping task decided to leave a room.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LeaveReason {
+    /// The bot is the only joined member and nobody else is invited.
+    Alone,
+    /// No activity was seen in the room for the given duration.
+    Inactive(Duration),
+}
+
+impl std::fmt::Display for LeaveReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LeaveReason::Alone => write!(f, "bot is the only member"),
+            LeaveReason::Inactive(idle) => {
+                write!(f, "no activity for {} seconds", idle.as_secs())
+            }
+        }
+    }
+}
+
+/// Why the housekeeping task decided to stay in a room.
+#[derive(Debug, Clone, PartialEq)]
+pub enum KeepReason {
+    /// The room is listed in `exempt_rooms`.
+    Exempt,
+    /// Someone other than the bot is joined or invited.
+    MembersPresent,
+    /// There was activity within the inactivity timeout, the given duration ago.
+    RecentActivity(Duration),
+    /// Neither leaving rule is enabled.
+    NoRules,
+}
+
+impl std::fmt::Display for KeepReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KeepReason::Exempt => write!(f, "room is exempt"),
+            KeepReason::MembersPresent => write!(f, "other members are present"),
+            KeepReason::RecentActivity(idle) => {
+                write!(f, "last activity {} seconds ago", idle.as_secs())
+            }
+            KeepReason::NoRules => write!(f, "no leaving rule is enabled"),
+        }
+    }
+}
+
+/// Snapshot of a joined room as seen by the housekeeping task.
+#[derive(Debug, Clone)]
+pub struct RoomSnapshot<'a> {
+    pub room_id: &'a str,
+    pub joined_members: u64,
+    pub invited_members: u64,
+    /// Time since the last event from someone other than the bot
+    pub idle: Duration,
+}
+
+/// Decide whether the bot should leave a room, returning the reason if so.
+pub fn leave_reason(room: &RoomSnapshot, config: &HousekeepingConfig) -> Option<LeaveReason> {
+    if config.exempt_rooms.iter().any(|r| r == room.room_id) {
+        return None;
+    }
+
+    if config.leave_when_alone && room.joined_members <= 1 && room.invited_members == 0 {
+        return Some(LeaveReason::Alone);
+    }
+
+    if let Some(timeout) = config.inactive_timeout_seconds
+        && room.idle >= Duration::from_secs(timeout)
+    {
+        return Some(LeaveReason::Inactive(room.idle));
+    }
+
+    None
+}
+
+/// Explain why the bot stays in a room that [`leave_reason`] keeps.
+///
+/// With inactivity enabled, recent activity is the reason, since it is what kept the room
+/// even when other members are present.
+pub fn keep_reason(room: &RoomSnapshot, config: &HousekeepingConfig) -> KeepReason {
+    if config.exempt_rooms.iter().any(|r| r == room.room_id) {
+        KeepReason::Exempt
+    } else if config.inactive_timeout_seconds.is_some() {
+        KeepReason::RecentActivity(room.idle)
+    } else if config.leave_when_alone {
+        KeepReason::MembersPresent
+    } else {
+        KeepReason::NoRules
+    }
+}
+
+/// Layout of the room activity file.
+#[derive(Debug, Serialize, Deserialize)]
+struct ActivityFile {
+    /// Seconds since the Unix epoch when tracking started, the idle time of rooms without activity
+    since: u64,
+    /// Seconds since the Unix epoch of the last activity per room
+    #[serde(default)]
+    rooms: BTreeMap<String, u64>,
+}
+
+/// Last activity per room, kept in a JSON file so that restarts don't reset the inactivity clock.
+#[derive(Debug)]
+pub struct RoomActivity {
+    path: PathBuf,
+    data: ActivityFile,
+}
+
+impl RoomActivity {
+    /// Load the activity from `path`, which may not exist yet.
+    pub fn load(path: &Path, now: SystemTime) -> Result<Self> {
+        let data = match fs::read_to_string(path) {
+            Ok(content) => serde_json::from_str(&content).with_context(|| {
+                format!("Failed to parse room activity file '{}'", path.display())
+            })?,
+            Err(e) if e.kind() == ErrorKind::NotFound => ActivityFile {
+                since: unix_seconds(now),
+                rooms: BTreeMap::new(),
+            },
+            Err(e) => {
+                return Err(e).with_context(|| {
+                    format!("Failed to read room activity file '{}'", path.display())
+                });
+            }
+        };
+        Ok(Self {
+            path: path.to_path_buf(),
+            data,
+        })
+    }
+
+    /// Note activity in a room.
+    pub fn record(&mut self, room_id: &str, at: SystemTime) {
+        self.data
+            .rooms
+            .insert(room_id.to_string(), unix_seconds(at));
+    }
+
+    /// Time since the last activity in a room, or since tracking started if there was none.
+    pub fn idle(&self, room_id: &str, now: SystemTime) -> Duration {
+        let last = self
+            .data
+            .rooms
+            .get(room_id)
+            .copied()
+            .unwrap_or(self.data.since);
+        Duration::from_secs(unix_seconds(now).saturating_sub(last))
+    }
+
+    /// Forget the activity of rooms the bot is no longer in.
+    pub fn retain(&mut self, mut joined: impl FnMut(&str) -> bool) {
+        self.data.rooms.retain(|room_id, _| joined(room_id));
+    }
+
+    /// Write the activity to a temporary file and rename it, so it is never left half written.
+    pub fn save(&self) -> Result<()> {
+        let content = serde_json::to_string_pretty(&self.data)
+            .context("Failed to serialize room activity")?;
+        let tmp_path = self.path.with_extension("tmp");
+        fs::write(&tmp_path, content).with_context(|| {
+            format!(
+                "Failed to write room activity file '{}'",
+                tmp_path.display()
+            )
+        })?;
+        fs::rename(&tmp_path, &self.path).with_context(|| {
+            format!(
+                "Failed to replace room activity file '{}'",
+                self.path.display()
+            )
+        })
+    }
+}
+
+fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> HousekeepingConfig {
+        HousekeepingConfig {
+            enabled: true,
+            inactive_timeout_seconds: Some(3600),
+            ..HousekeepingConfig::default()
+        }
+    }
+
+    fn snapshot(
+        joined_members: u64,
+        invited_members: u64,
+        idle_seconds: u64,
+    ) -> RoomSnapshot<'static> {
+        RoomSnapshot {
+            room_id: "!room:example.com",
+            joined_members,
+            invited_members,
+            idle: Duration::from_secs(idle_seconds),
+        }
+    }
+
+    #[test]
+    fn test_leave_reason_alone() {
+        // Given a room where the bot is the only joined member
+        let room = snapshot(1, 0, 10);
+
+        // When deciding whether to leave
+        let reason = leave_reason(&room, &config());
+
+        // Then the bot should leave because it is alone
+        assert_eq!(reason, Some(LeaveReason::Alone));
+    }
+
+    #[test]
+    fn test_leave_reason_keeps_room_with_pending_invites() {
+        // Given a room where the bot is alone but someone has been invited
+        let room = snapshot(1, 1, 10);
+
+        // When deciding whether to leave
+        let reason = leave_reason(&room, &config());
+
+        // Then the bot should stay
+        assert_eq!(reason, None);
+    }
+
+    #[test]
+    fn test_leave_reason_inactive() {
+        // Given a busy room without activity and one with recent activity
+        let idle_room = snapshot(5, 0, 7200);
+        let active_room = snapshot(5, 0, 60);
+
+        // When deciding whether to leave
+        let idle_reason = leave_reason(&idle_room, &config());
+        let active_reason = leave_reason(&active_room, &config());
+
+        // Then only the idle room should be left
+        assert_eq!(
+            idle_reason,
+            Some(LeaveReason::Inactive(Duration::from_secs(7200)))
+        );
+        assert_eq!(active_reason, None);
+    }
+
+    #[test]
+    fn test_leave_reason_respects_disabled_rules_and_exemptions() {
+        // Given a config with both rules disabled and one with an exempt room
+        let disabled = HousekeepingConfig {
+            enabled: true,
+            leave_when_alone: false,
+            inactive_timeout_seconds: None,
+            ..HousekeepingConfig::default()
+        };
+        let exempt = HousekeepingConfig {
+            exempt_rooms: vec!["!room:example.com".to_string()],
+            ..config()
+        };
+        let room = snapshot(1, 0, 7200);
+
+        // When deciding whether to leave an empty, idle room
+        // Then neither config should cause the bot to leave
+        assert_eq!(leave_reason(&room, &disabled), None);
+        assert_eq!(leave_reason(&room, &exempt), None);
+    }
+
+    #[test]
+    fn test_keep_reason() {
+        // Given an active room, an exempt room, and configs without inactivity or any rules
+        let room = snapshot(5, 0, 60);
+        let exempt = HousekeepingConfig {
+            exempt_rooms: vec!["!room:example.com".to_string()],
+            ..config()
+        };
+        let alone_only = HousekeepingConfig {
+            inactive_timeout_seconds: None,
+            ..config()
+        };
+        let disabled = HousekeepingConfig {
+            leave_when_alone: false,
+            ..alone_only.clone()
+        };
+
+        // When explaining why the room is kept
+        // Then the rule that kept it should be named
+        assert_eq!(
+            keep_reason(&room, &config()),
+            KeepReason::RecentActivity(Duration::from_secs(60))
+        );
+        assert_eq!(keep_reason(&room, &exempt), KeepReason::Exempt);
+        assert_eq!(keep_reason(&room, &alone_only), KeepReason::MembersPresent);
+        assert_eq!(keep_reason(&room, &disabled), KeepReason::NoRules);
+    }
+
+    #[test]
+    fn test_room_activity_survives_restarts() {
+        // Given activity tracked since an hour ago, with one active room
+        let path = Path::new("test_room_activity.json");
+        let _ = fs::remove_file(path);
+        let start = UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let mut activity = RoomActivity::load(path, start).unwrap();
+        activity.record("!active:example.com", start + Duration::from_secs(3000));
+        activity.record("!left:example.com", start);
+        activity.retain(|room_id| room_id != "!left:example.com");
+        activity.save().unwrap();
+
+        // When loading it again after a restart
+        let now = start + Duration::from_secs(3600);
+        let reloaded = RoomActivity::load(path, now).unwrap();
+
+        // Then idle times should still count from before the restart
+        assert_eq!(
+            reloaded.idle("!active:example.com", now),
+            Duration::from_secs(600)
+        );
+        assert_eq!(
+            reloaded.idle("!quiet:example.com", now),
+            Duration::from_secs(3600)
+        );
+        assert!(!fs::read_to_string(path).unwrap().contains("!left"));
+
+        // Clean up
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,231 @@
+use crate::Config;
+use crate::health::{Health, Readiness};
+use crate::metrics::Metrics;
+use anyhow::{Context, Result};
+use http_body_util::Full;
+use hyper::body::{Bytes, Incoming};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use tokio::net::TcpListener;
+use tracing::{debug, warn};
+
+/// Path of the liveness check, answered whenever the process is up.
+pub const HEALTHZ_PATH: &str = "/healthz";
+/// Path of the readiness check, answered once the bot is syncing.
+pub const READYZ_PATH: &str = "/readyz";
+
+/// What one HTTP listener answers, everything else being 404.
+#[derive(Clone, Default)]
+pub struct Endpoints {
+    /// Metrics and the path they are served at
+    metrics: Option<(Metrics, String)>,
+    /// `/healthz` and `/readyz`
+    health: Option<Health>,
+}
+
+impl Endpoints {
+    /// What is served, for logging.
+    pub fn describe(&self) -> &'static str {
+        match (&self.metrics, &self.health) {
+            (Some(_), Some(_)) => "metrics and health checks",
+            (Some(_), None) => "metrics",
+            (None, _) => "health checks",
+        }
+    }
+}
+
+/// The enabled HTTP endpoints, grouped by the address they listen on.
+///
+/// Metrics and health checks configured with the same address share a listener.
+pub fn http_listeners(
+    config: &Config,
+    metrics: &Metrics,
+    health: &Health,
+) -> Vec<(String, Endpoints)> {
+    let mut listeners: Vec<(String, Endpoints)> = Vec::new();
+    if config.metrics.enabled {
+        listeners.push((
+            config.metrics.listen_address.clone(),
+            Endpoints {
+                metrics: Some((metrics.clone(), config.metrics.path.clone())),
+                health: None,
+            },
+        ));
+    }
+    if config.health.enabled {
+        let address = &config.health.listen_address;
+        match listeners
+            .iter_mut()
+            .find(|(other, _)| same_address(other, address))
+        {
+            Some((_, endpoints)) => endpoints.health = Some(health.clone()),
+            None => listeners.push((
+                address.clone(),
+                Endpoints {
+                    metrics: None,
+                    health: Some(health.clone()),
+                },
+            )),
+        }
+    }
+    listeners
+}
+
+/// Whether two configured listen addresses are the same, e.g. `127.0.0.1:80` and `127.0.0.1:080`.
+pub fn same_address(a: &str, b: &str) -> bool {
+    match (a.parse::<SocketAddr>(), b.parse::<SocketAddr>()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+/// Bind an HTTP listener, so that a port in use is reported at startup.
+pub async fn bind_http(address: &str) -> Result<TcpListener> {
+    TcpListener::bind(address)
+        .await
+        .with_context(|| format!("Failed to listen on '{}'", address))
+}
+
+/// Answer HTTP requests for the endpoints until the task is dropped.
+pub async fn serve_http(listener: TcpListener, endpoints: Endpoints) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                warn!(error = %e, "Failed to accept HTTP connection");
+                continue;
+            }
+        };
+        let endpoints = endpoints.clone();
+        tokio::spawn(async move {
+            let service = service_fn(|request: Request<Incoming>| {
+                let response = respond(&request, &endpoints);
+                async move { Ok::<_, Infallible>(response) }
+            });
+            if let Err(e) = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+            {
+                debug!(error = %e, "HTTP connection failed");
+            }
+        });
+    }
+}
+
+fn respond(request: &Request<Incoming>, endpoints: &Endpoints) -> Response<Full<Bytes>> {
+    let path = request.uri().path();
+    let metrics = endpoints
+        .metrics
+        .as_ref()
+        .filter(|(_, metrics_path)| metrics_path == path);
+    let health = endpoints
+        .health
+        .as_ref()
+        .filter(|_| path == HEALTHZ_PATH || path == READYZ_PATH);
+
+    let (status, content_type, body) = if metrics.is_none() && health.is_none() {
+        (
+            StatusCode::NOT_FOUND,
+            "text/plain",
+            "Not found\n".to_string(),
+        )
+    } else if request.method() != Method::GET {
+        (
+            StatusCode::METHOD_NOT_ALLOWED,
+            "text/plain",
+            "Method not allowed\n".to_string(),
+        )
+    } else if let Some((metrics, _)) = metrics {
+        (
+            StatusCode::OK,
+            "text/plain; version=0.0.4",
+            metrics.encode(),
+        )
+    } else if path == HEALTHZ_PATH {
+        (StatusCode::OK, "text/plain", "ok\n".to_string())
+    } else {
+        match health.expect("Health should be served").readiness() {
+            Readiness::Ready => (StatusCode::OK, "text/plain", "ready\n".to_string()),
+            Readiness::NotReady(reason) => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "text/plain",
+                format!("not ready: {}\n", reason),
+            ),
+        }
+    };
+    Response::builder()
+        .status(status)
+        .header("Content-Type", content_type)
+        .body(Full::new(Bytes::from(body)))
+        .expect("Response should be valid")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::health::HealthConfig;
+
+    async fn get(address: SocketAddr, path: &str) -> (u16, String) {
+        let response = matrix_sdk::reqwest::get(format!("http://{}{}", address, path))
+            .await
+            .unwrap();
+        let status = response.status().as_u16();
+        (status, response.text().await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_serve_metrics() {
+        // Given a metrics listener on a free port
+        let listener = bind_http("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let metrics = Metrics::new();
+        metrics.ignored_message();
+        let endpoints = Endpoints {
+            metrics: Some((metrics, "/metrics".to_string())),
+            health: None,
+        };
+        tokio::spawn(serve_http(listener, endpoints));
+
+        // When requesting the metrics and other paths
+        let (found_status, found_body) = get(address, "/metrics").await;
+        let (missing_status, _) = get(address, "/other").await;
+        let (health_status, _) = get(address, HEALTHZ_PATH).await;
+
+        // Then only the metrics path should answer
+        assert_eq!(found_status, 200);
+        assert!(found_body.contains("matrix_bot_help_ignored_messages_total 1"));
+        assert_eq!(missing_status, 404);
+        assert_eq!(health_status, 404);
+    }
+
+    #[tokio::test]
+    async fn test_serve_health() {
+        // Given a health listener for a bot that has logged in but not synced
+        let listener = bind_http("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let health = Health::new(&HealthConfig::default());
+        health.logged_in();
+        let endpoints = Endpoints {
+            metrics: None,
+            health: Some(health.clone()),
+        };
+        tokio::spawn(serve_http(listener, endpoints));
+
+        // When checking liveness and readiness before and after the initial sync
+        let (live_status, _) = get(address, HEALTHZ_PATH).await;
+        let (starting_status, starting_body) = get(address, READYZ_PATH).await;
+        health.synced();
+        let (ready_status, ready_body) = get(address, READYZ_PATH).await;
+
+        // Then the bot should be alive throughout, and ready only after syncing
+        assert_eq!(live_status, 200);
+        assert_eq!(starting_status, 503);
+        assert_eq!(starting_body, "not ready: initial sync not completed\n");
+        assert_eq!(ready_status, 200);
+        assert_eq!(ready_body, "ready\n");
+    }
+}
@@ -1,7 +1,60 @@
 use anyhow::{Context, Result, anyhow};
+use matrix_sdk::ruma::events::room::message::RoomMessageEventContent;
+use serde::{Deserialize, Deserializer};
+use std::collections::BTreeMap;
 use std::fs;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use toml::Value;
+
+pub mod admin;
+pub mod check;
+pub mod commands;
+pub mod faq;
+pub mod health;
+pub mod housekeeping;
+pub mod http;
+pub mod logfile;
+pub mod logging;
+pub mod metrics;
+pub mod notifications;
+pub mod overrides;
+pub mod pidfile;
+pub mod retry;
+pub mod session;
+pub mod shutdown;
+pub mod stats;
+pub mod systemd;
+pub mod verification;
+
+pub use admin::{ADMIN_HELP, AdminCommand, IgnoreList, format_uptime, parse_admin_command};
+pub use check::check_config;
+pub use commands::{
+    CommandConfig, CustomCommand, Delivery, find_command, help_with_commands, is_help_command,
+};
+pub use faq::{FaqConfig, FaqCooldowns, FaqEntry, FaqMatcher};
+pub use health::{Health, HealthConfig, Readiness};
+pub use housekeeping::{
+    KeepReason, LeaveReason, RoomActivity, RoomSnapshot, keep_reason, leave_reason,
+};
+pub use http::{Endpoints, bind_http, http_listeners, serve_http};
+pub use logfile::{LogFile, LogRotationConfig};
+pub use logging::{LogFormat, LoggingConfig, init_logging};
+pub use metrics::{InviteOutcome, MetricTotals, Metrics, MetricsConfig, WelcomeOutcome};
+pub use notifications::{NotificationLevel, Notifications, NotificationsConfig};
+pub use overrides::{ENV_PREFIX, ValueSource};
+pub use pidfile::{PidFile, PidStatus};
+pub use retry::{
+    GiveUpAction, ReconnectPolicy, RetryPolicy, is_retryable_sync_error, retry_with_policy,
+    run_with_reconnect,
+};
+pub use session::{
+    AuthenticationError, EXIT_AUTH_FAILURE, configured_token_replaces, is_rejected_credentials,
+    is_unknown_token, load_session, save_session,
+};
+pub use shutdown::{OperationGuard, Shutdown};
+pub use stats::{RoomReport, StatsConfig, StatsPrivacy, StatsReport, UsageStats};
+pub use systemd::{Watchdog, notify_ready, notify_status, notify_stopping, watchdog_timeout};
+pub use verification::{accept_verification_request, is_verification_allowed};
 
 /// Help format options for displaying help text.
 #[derive(Debug, Clone, PartialEq, Default)]
@@ -28,6 +81,13 @@ impl FromStr for HelpFormat {
     }
 }
 
+impl<'de> Deserialize<'de> for HelpFormat {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        HelpFormat::from_str(&s).map_err(serde::de::Error::custom)
+    }
+}
+
 impl std::fmt::Display for HelpFormat {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -39,7 +99,8 @@ impl std::fmt::Display for HelpFormat {
 }
 
 /// Configuration for bot message filtering.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct BotFilteringConfig {
     /// Whether to ignore messages from bot itself
     pub ignore_self: bool,
@@ -50,7 +111,8 @@ pub struct BotFilteringConfig {
 }
 
 /// Configuration for join detection.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct JoinDetectionConfig {
     /// Whether to detect user joins at all
     pub enabled: bool,
@@ -68,6 +130,50 @@ pub struct JoinDetectionConfig {
     pub welcome_timeout_seconds: u64,
 }
 
+/// Configuration for the background task that leaves unused rooms.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HousekeepingConfig {
+    /// Whether to run the housekeeping task at all
+    pub enabled: bool,
+    /// How often to check joined rooms, in seconds
+    pub interval_seconds: u64,
+    /// Whether to leave rooms where the bot is the only joined member
+    pub leave_when_alone: bool,
+    /// Leave rooms without activity for this many seconds (None = never)
+    pub inactive_timeout_seconds: Option<u64>,
+    /// Whether to also forget rooms after leaving them
+    pub forget_rooms: bool,
+    /// Room IDs that are never left by housekeeping
+    pub exempt_rooms: Vec<String>,
+    /// JSON file the last activity per room is kept in, relative to the working directory
+    pub activity_file: String,
+}
+
+/// Configuration for the on-disk state store.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StoreConfig {
+    /// Directory of the SQLite store, relative to the working directory (None = in memory)
+    pub path: Option<String>,
+    /// Passphrase used to encrypt the store
+    pub passphrase: Option<String>,
+    /// File to read the passphrase from instead
+    pub passphrase_file: Option<String>,
+}
+
+/// Configuration for end-to-end encryption.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EncryptionConfig {
+    /// Whether to support encrypted rooms
+    pub enabled: bool,
+    /// Recovery key used to restore cross-signing secrets and the key backup
+    pub recovery_key: Option<String>,
+    /// File to read the recovery key from instead
+    pub recovery_key_file: Option<String>,
+}
+
 impl Default for BotFilteringConfig {
     fn default() -> Self {
         Self {
@@ -92,82 +198,513 @@ impl Default for JoinDetectionConfig {
     }
 }
 
+impl Default for HousekeepingConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            interval_seconds: 3600,
+            leave_when_alone: true,
+            inactive_timeout_seconds: None,
+            forget_rooms: false,
+            exempt_rooms: Vec::new(),
+            activity_file: "room-activity.json".to_string(),
+        }
+    }
+}
+
+/// Layout of the config file as written by users.
+///
+/// Required fields are optional here so that missing ones can be reported by
+/// name once the file has been parsed.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    homeserver: Option<String>,
+    username: Option<String>,
+    access_token: Option<String>,
+    access_token_file: Option<String>,
+    refresh_token: Option<String>,
+    refresh_token_file: Option<String>,
+    password: Option<String>,
+    password_file: Option<String>,
+    #[serde(default = "default_session_file")]
+    session_file: String,
+    #[serde(default = "default_device_id")]
+    device_id: String,
+    #[serde(default = "default_log_file")]
+    log_file: String,
+    #[serde(default)]
+    log_rotation: LogRotationConfig,
+    #[serde(default = "default_pid_file")]
+    pid_file: String,
+    #[serde(default = "default_shutdown_timeout_seconds")]
+    shutdown_timeout_seconds: u64,
+    #[serde(default = "default_working_directory")]
+    working_directory: String,
+    help_file: Option<String>,
+    #[serde(default)]
+    help_format: HelpFormat,
+    #[serde(default)]
+    commands: Vec<CommandConfig>,
+    #[serde(default)]
+    faq: FaqConfig,
+    #[serde(default)]
+    admins: Vec<String>,
+    admin_room: Option<String>,
+    #[serde(default = "default_admin_power_level")]
+    admin_power_level: i64,
+    #[serde(default = "default_ignore_file")]
+    ignore_file: String,
+    #[serde(default)]
+    bot_filtering: BotFilteringConfig,
+    #[serde(default)]
+    join_detection: JoinDetectionConfig,
+    #[serde(default)]
+    join_retry: RetryPolicy,
+    #[serde(default)]
+    sync_retry: ReconnectPolicy,
+    #[serde(default)]
+    housekeeping: HousekeepingConfig,
+    #[serde(default)]
+    store: StoreConfig,
+    #[serde(default)]
+    encryption: EncryptionConfig,
+    #[serde(default)]
+    logging: LoggingConfig,
+    #[serde(default)]
+    metrics: MetricsConfig,
+    #[serde(default)]
+    health: HealthConfig,
+    #[serde(default)]
+    notifications: NotificationsConfig,
+    #[serde(default)]
+    stats: StatsConfig,
+}
+
+fn default_session_file() -> String {
+    "session.json".to_string()
+}
+
+fn default_device_id() -> String {
+    "matrix-bot-help".to_string()
+}
+
+fn default_log_file() -> String {
+    "bot.log".to_string()
+}
+
+fn default_pid_file() -> String {
+    "matrix-bot-help.pid".to_string()
+}
+
+fn default_shutdown_timeout_seconds() -> u64 {
+    8
+}
+
+fn default_working_directory() -> String {
+    ".".to_string()
+}
+
+fn default_admin_power_level() -> i64 {
+    100
+}
+
+fn default_ignore_file() -> String {
+    "ignored-users.json".to_string()
+}
+
 #[derive(Debug)]
 pub struct Config {
     pub homeserver: String,
     pub username: String,
-    pub access_token: String,
+    pub access_token: Option<String>,
+    pub refresh_token: Option<String>,
+    pub password: Option<String>,
+    pub session_file: String,
+    pub device_id: String,
     pub log_file: String,
+    pub log_rotation: LogRotationConfig,
+    pub pid_file: String,
+    /// Seconds to wait for running operations to finish on shutdown
+    pub shutdown_timeout_seconds: u64,
     pub working_dir: String,
     pub help_file: String,
     pub help_format: HelpFormat,
+    /// Commands with static responses, listed by `!help`
+    pub commands: Vec<CommandConfig>,
+    /// Answers to frequently asked questions, sent without a command
+    pub faq: FaqConfig,
+    pub admins: Vec<String>,
+    /// Room whose members with at least `admin_power_level` are also admins
+    pub admin_room: Option<String>,
+    pub admin_power_level: i64,
+    /// Users ignored with `!bot ignore`, relative to the working directory
+    pub ignore_file: String,
     pub bot_filtering: BotFilteringConfig,
     pub join_detection: JoinDetectionConfig,
+    pub join_retry: RetryPolicy,
+    pub sync_retry: ReconnectPolicy,
+    pub housekeeping: HousekeepingConfig,
+    pub store: StoreConfig,
+    pub encryption: EncryptionConfig,
+    pub logging: LoggingConfig,
+    pub metrics: MetricsConfig,
+    pub health: HealthConfig,
+    pub notifications: NotificationsConfig,
+    pub stats: StatsConfig,
+    /// Values that were not read from the config file itself, keyed by dotted name
+    pub sources: BTreeMap<String, ValueSource>,
+}
+
+/// Secrets that may also be given as `<key>_file`, as dotted keys.
+const SECRET_KEYS: [&str; 5] = [
+    "access_token",
+    "refresh_token",
+    "password",
+    "store.passphrase",
+    "encryption.recovery_key",
+];
+
+/// Resolve a secret that may also be given as `<key>_file`.
+fn resolve_secret(
+    key: &str,
+    value: Option<String>,
+    file: Option<&str>,
+    sources: &mut BTreeMap<String, ValueSource>,
+) -> Result<Option<String>> {
+    match (value, file) {
+        (Some(_), Some(_)) => Err(anyhow!("Only one of '{key}' and '{key}_file' may be set")),
+        (None, Some(path)) => {
+            let secret = overrides::read_secret_file(path)?;
+            sources.insert(key.to_string(), ValueSource::SecretFile(path.to_string()));
+            Ok(Some(secret))
+        }
+        (value, None) => Ok(value),
+    }
 }
 
 impl Config {
     pub fn from_toml(toml_str: &str) -> Result<Self> {
-        let config: Value =
+        Self::from_toml_with_env(toml_str, std::iter::empty())
+    }
+
+    /// Parse the config, applying `MATRIX_BOT_HELP_*` overrides from the given
+    /// environment variables and reading `*_file` secrets.
+    pub fn from_toml_with_env<I>(toml_str: &str, env: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        // Parse the file on its own first, so that errors point at its lines
+        let mut file: ConfigFile =
             toml::from_str(toml_str).map_err(|e| anyhow!("Failed to parse TOML: {}", e))?;
 
+        let env_overrides = overrides::env_overrides(env);
+        if !env_overrides.is_empty() {
+            let mut table: toml::Table = toml::from_str(toml_str)?;
+            overrides::apply_overrides(&mut table, &env_overrides, |table| {
+                ConfigFile::deserialize(toml::Value::Table(table.clone())).is_ok()
+            })?;
+            overrides::replace_secret_forms(&mut table, &env_overrides, &SECRET_KEYS);
+            file = ConfigFile::deserialize(toml::Value::Table(table))
+                .map_err(|e| anyhow!("Invalid environment override: {}", e))?;
+        }
+        let mut sources: BTreeMap<String, ValueSource> = env_overrides
+            .iter()
+            .map(|o| (o.key(), ValueSource::Env(o.var.clone())))
+            .collect();
+
+        file.access_token = resolve_secret(
+            "access_token",
+            file.access_token,
+            file.access_token_file.as_deref(),
+            &mut sources,
+        )?;
+        file.refresh_token = resolve_secret(
+            "refresh_token",
+            file.refresh_token,
+            file.refresh_token_file.as_deref(),
+            &mut sources,
+        )?;
+        file.password = resolve_secret(
+            "password",
+            file.password,
+            file.password_file.as_deref(),
+            &mut sources,
+        )?;
+        file.store.passphrase = resolve_secret(
+            "store.passphrase",
+            file.store.passphrase,
+            file.store.passphrase_file.as_deref(),
+            &mut sources,
+        )?;
+        file.encryption.recovery_key = resolve_secret(
+            "encryption.recovery_key",
+            file.encryption.recovery_key,
+            file.encryption.recovery_key_file.as_deref(),
+            &mut sources,
+        )?;
+
+        let homeserver = file
+            .homeserver
+            .ok_or_else(|| anyhow!("Missing 'homeserver' in config file"))?;
+        let username = file
+            .username
+            .ok_or_else(|| anyhow!("Missing 'username' in config file"))?;
+        if file.access_token.is_none() && file.password.is_none() {
+            return Err(anyhow!(
+                "Missing 'access_token' or 'password' in config file"
+            ));
+        }
+        let help_file = file
+            .help_file
+            .ok_or_else(|| anyhow!("Missing 'help_file' in config file"))?;
+        if file.join_retry.max_attempts == 0 {
+            return Err(anyhow!("'join_retry.max_attempts' must be at least 1"));
+        }
+        if file.sync_retry.initial_delay_seconds == 0 {
+            return Err(anyhow!(
+                "'sync_retry.initial_delay_seconds' must be at least 1"
+            ));
+        }
+        if file.housekeeping.interval_seconds == 0 {
+            return Err(anyhow!(
+                "'housekeeping.interval_seconds' must be at least 1"
+            ));
+        }
+        commands::validate_commands(&file.commands)?;
+        faq::validate_faq(&file.faq, &file.commands)?;
+        if file.log_rotation.max_size_mb == Some(0) {
+            return Err(anyhow!("'log_rotation.max_size_mb' must be at least 1"));
+        }
+        if file.log_rotation.interval_seconds == Some(0) {
+            return Err(anyhow!(
+                "'log_rotation.interval_seconds' must be at least 1"
+            ));
+        }
+        file.logging.env_filter()?;
+        if file
+            .metrics
+            .listen_address
+            .parse::<std::net::SocketAddr>()
+            .is_err()
+        {
+            return Err(anyhow!(
+                "'metrics.listen_address' must be an IP address and port, e.g. 127.0.0.1:9184"
+            ));
+        }
+        if !file.metrics.path.starts_with('/') {
+            return Err(anyhow!("'metrics.path' must start with '/'"));
+        }
+        if file
+            .health
+            .listen_address
+            .parse::<std::net::SocketAddr>()
+            .is_err()
+        {
+            return Err(anyhow!(
+                "'health.listen_address' must be an IP address and port, e.g. 127.0.0.1:9185"
+            ));
+        }
+        if file.health.max_sync_age_seconds == 0 {
+            return Err(anyhow!("'health.max_sync_age_seconds' must be at least 1"));
+        }
+        if file.metrics.enabled
+            && file.health.enabled
+            && http::same_address(&file.metrics.listen_address, &file.health.listen_address)
+            && [http::HEALTHZ_PATH, http::READYZ_PATH].contains(&file.metrics.path.as_str())
+        {
+            return Err(anyhow!(
+                "'metrics.path' must not be {} or {} when sharing the health listener",
+                http::HEALTHZ_PATH,
+                http::READYZ_PATH
+            ));
+        }
+        if file.notifications.enabled && file.admin_room.is_none() {
+            return Err(anyhow!(
+                "'notifications.enabled' needs 'admin_room' to post to"
+            ));
+        }
+        if let Some(ref time) = file.notifications.daily_summary_at {
+            notifications::parse_time_of_day(time)
+                .context("Invalid 'notifications.daily_summary_at'")?;
+        }
+        if file.notifications.batch_seconds == 0 {
+            return Err(anyhow!("'notifications.batch_seconds' must be at least 1"));
+        }
+        if file.notifications.max_lines == 0 {
+            return Err(anyhow!("'notifications.max_lines' must be at least 1"));
+        }
+
         Ok(Config {
-            homeserver: config
-                .get("homeserver")
-                .and_then(|v| v.as_str())
-                .ok_or_else(|| anyhow!("Missing 'homeserver' in config file"))?
-                .to_string(),
-            username: config
-                .get("username")
-                .and_then(|v| v.as_str())
-                .ok_or_else(|| anyhow!("Missing 'username' in config file"))?
-                .to_string(),
-            access_token: config
-                .get("access_token")
-                .and_then(|v| v.as_str())
-                .ok_or_else(|| anyhow!("Missing 'access_token' in config file"))?
-                .to_string(),
-            log_file: config
-                .get("log_file")
-                .and_then(|v| v.as_str())
-                .unwrap_or("bot.log")
-                .to_string(),
-            working_dir: config
-                .get("working_directory")
-                .and_then(|v| v.as_str())
-                .unwrap_or(".")
-                .to_string(),
-            help_file: config
-                .get("help_file")
-                .and_then(|v| v.as_str())
-                .ok_or_else(|| anyhow!("Missing 'help_file' in config file"))?
-                .to_string(),
-            help_format: config
-                .get("help_format")
-                .and_then(|v| v.as_str())
-                .map(HelpFormat::from_str)
-                .transpose()?
-                .unwrap_or_default(),
-            bot_filtering: parse_bot_filtering_config(&config)?,
-            join_detection: parse_join_detection_config(&config)?,
+            homeserver,
+            username,
+            access_token: file.access_token,
+            refresh_token: file.refresh_token,
+            password: file.password,
+            session_file: file.session_file,
+            device_id: file.device_id,
+            log_file: file.log_file,
+            log_rotation: file.log_rotation,
+            pid_file: file.pid_file,
+            shutdown_timeout_seconds: file.shutdown_timeout_seconds,
+            working_dir: file.working_directory,
+            help_file,
+            help_format: file.help_format,
+            commands: file.commands,
+            faq: file.faq,
+            admins: file.admins,
+            admin_room: file.admin_room,
+            admin_power_level: file.admin_power_level,
+            ignore_file: file.ignore_file,
+            bot_filtering: file.bot_filtering,
+            join_detection: file.join_detection,
+            join_retry: file.join_retry,
+            sync_retry: file.sync_retry,
+            housekeeping: file.housekeeping,
+            store: file.store,
+            encryption: file.encryption,
+            logging: file.logging,
+            metrics: file.metrics,
+            health: file.health,
+            notifications: file.notifications,
+            stats: file.stats,
+            sources,
         })
     }
 
+    /// Make the working directory absolute, so that the paths derived from it
+    /// still point at the same files after a daemon changes into it.
+    pub fn resolve_working_dir(&mut self) -> Result<()> {
+        let working_dir = std::path::absolute(&self.working_dir)
+            .with_context(|| format!("Invalid working directory '{}'", self.working_dir))?;
+        self.working_dir = working_dir
+            .into_os_string()
+            .into_string()
+            .map_err(|path| anyhow!("Working directory '{}' is not valid UTF-8", path.display()))?;
+        Ok(())
+    }
+
+    /// Path of the persisted session file, relative to the working directory.
+    pub fn session_path(&self) -> PathBuf {
+        Path::new(&self.working_dir).join(&self.session_file)
+    }
+
+    /// Path of the file with users ignored by admin command, relative to the working directory.
+    pub fn ignore_path(&self) -> PathBuf {
+        Path::new(&self.working_dir).join(&self.ignore_file)
+    }
+
+    /// Path of the usage statistics file, relative to the working directory.
+    pub fn stats_path(&self) -> PathBuf {
+        Path::new(&self.working_dir).join(&self.stats.file)
+    }
+
+    /// Path of the room activity file, relative to the working directory.
+    pub fn activity_path(&self) -> PathBuf {
+        Path::new(&self.working_dir).join(&self.housekeeping.activity_file)
+    }
+
+    /// Path of the PID file, relative to the working directory.
+    pub fn pid_path(&self) -> PathBuf {
+        Path::new(&self.working_dir).join(&self.pid_file)
+    }
+
+    /// Path of the SQLite state store, relative to the working directory.
+    ///
+    /// Encryption needs a persistent store, so it defaults to `store` when
+    /// encryption is enabled and no path is configured.
+    pub fn store_path(&self) -> Option<PathBuf> {
+        let path = match self.store.path {
+            Some(ref path) => path.as_str(),
+            None if self.encryption.enabled => "store",
+            None => return None,
+        };
+        Some(Path::new(&self.working_dir).join(path))
+    }
+
     pub fn print(&self) {
         println!("Configuration:");
         println!("  Homeserver: {}", self.homeserver);
         println!("  Username: {}", self.username);
         println!(
             "  Access Token: {}",
-            if self.access_token.is_empty() {
-                "[empty]"
+            match self.access_token.as_deref() {
+                None => "[not set]",
+                Some("") => "[empty]",
+                Some(_) => "[set]",
+            }
+        );
+        println!(
+            "  Refresh Token: {}",
+            if self.refresh_token.is_some() {
+                "[set]"
             } else {
+                "[not set]"
+            }
+        );
+        println!(
+            "  Password: {}",
+            if self.password.is_some() {
                 "[set]"
+            } else {
+                "[not set]"
             }
         );
+        println!("  Session File: {}", self.session_path().display());
+        println!("  Device ID: {}", self.device_id);
         println!("  Log File: {}", self.log_file);
+        println!("  Log Rotation:");
+        match self.log_rotation.max_size_mb {
+            Some(max_size_mb) => println!("    Max Size: {} MB", max_size_mb),
+            None => println!("    Max Size: [unlimited]"),
+        }
+        match self.log_rotation.interval_seconds {
+            Some(interval) => println!("    Interval: {} seconds", interval),
+            None => println!("    Interval: [never]"),
+        }
+        println!("    Keep Files: {}", self.log_rotation.keep_files);
+        println!("  PID File: {}", self.pid_path().display());
+        println!(
+            "  Shutdown Timeout: {} seconds",
+            self.shutdown_timeout_seconds
+        );
         println!("  Working Directory: {}", self.working_dir);
         println!("  Help File: {}", self.help_file);
         println!("  Help Format: {}", self.help_format);
+        if !self.commands.is_empty() {
+            println!("  Commands:");
+            for command in &self.commands {
+                let aliases = if command.aliases.is_empty() {
+                    String::new()
+                } else {
+                    format!(" (aliases: {})", command.aliases.join(", "))
+                };
+                let response = match (&command.file, &command.text) {
+                    (Some(file), _) => file.clone(),
+                    _ => "[inline text]".to_string(),
+                };
+                println!(
+                    "    !{}{}: {}, {}, {}",
+                    command.name, aliases, response, command.format, command.delivery
+                );
+            }
+        }
+        if !self.admins.is_empty() {
+            println!("  Admins:");
+            for admin in &self.admins {
+                println!("    {}", admin);
+            }
+        } else {
+            println!("  Admins: [none]");
+        }
+        match self.admin_room {
+            Some(ref admin_room) => println!(
+                "  Admin Room: {} (power level {} and above)",
+                admin_room, self.admin_power_level
+            ),
+            None => println!("  Admin Room: [not set]"),
+        }
+        println!("  Ignore File: {}", self.ignore_path().display());
         println!("  Bot Filtering:");
         println!("    Ignore Self: {}", self.bot_filtering.ignore_self);
         println!("    Ignore Bots: {}", self.bot_filtering.ignore_bots);
@@ -205,118 +742,155 @@ impl Config {
                 self.join_detection.welcome_timeout_seconds
             );
         }
-    }
-}
-
-/// Parse bot filtering configuration from TOML value.
-fn parse_bot_filtering_config(config: &Value) -> Result<BotFilteringConfig> {
-    let bot_filtering_config = config.get("bot_filtering");
-
-    if let Some(bot_config) = bot_filtering_config {
-        // Parse ignore_self
-        let ignore_self = bot_config
-            .get("ignore_self")
-            .and_then(|v| v.as_bool())
-            .unwrap_or(true);
-
-        // Parse ignore_bots
-        let ignore_bots = bot_config
-            .get("ignore_bots")
-            .and_then(|v| v.as_bool())
-            .unwrap_or(false);
-
-        // Parse ignored_users
-        let ignored_users = bot_config
-            .get("ignored_users")
-            .and_then(|v| v.as_array())
-            .map(|arr| {
-                arr.iter()
-                    .filter_map(|v| v.as_str())
-                    .map(|s| s.to_string())
-                    .collect()
-            })
-            .unwrap_or_default();
-
-        Ok(BotFilteringConfig {
-            ignore_self,
-            ignore_bots,
-            ignored_users,
-        })
-    } else {
-        // No bot_filtering section, use defaults
-        Ok(BotFilteringConfig::default())
-    }
-}
-
-/// Parse join detection configuration from TOML value.
-fn parse_join_detection_config(config: &Value) -> Result<JoinDetectionConfig> {
-    let join_detection_config = config.get("join_detection");
-
-    if let Some(join_config) = join_detection_config {
-        // Parse enabled
-        let enabled = join_config
-            .get("enabled")
-            .and_then(|v| v.as_bool())
-            .unwrap_or(true);
-
-        // Parse monitored_rooms
-        let monitored_rooms = join_config
-            .get("monitored_rooms")
-            .and_then(|v| v.as_array())
-            .map(|arr| {
-                arr.iter()
-                    .filter_map(|v| v.as_str())
-                    .map(|s| s.to_string())
-                    .collect()
-            })
-            .unwrap_or_default();
-
-        // Parse send_welcome
-        let send_welcome = join_config
-            .get("send_welcome")
-            .and_then(|v| v.as_bool())
-            .unwrap_or(false);
-
-        // Parse welcome_message
-        let welcome_message = join_config
-            .get("welcome_message")
-            .and_then(|v| v.as_str())
-            .unwrap_or("Welcome to the room! Type !help for assistance.")
-            .to_string();
-
-        // Parse welcome_format
-        let welcome_format = join_config
-            .get("welcome_format")
-            .and_then(|v| v.as_str())
-            .map(HelpFormat::from_str)
-            .transpose()?
-            .unwrap_or_default();
-
-        // Parse welcome_file
-        let welcome_file = join_config
-            .get("welcome_file")
-            .and_then(|v| v.as_str())
-            .map(|s| s.to_string());
-
-        // Parse welcome_timeout_seconds
-        let welcome_timeout_seconds = join_config
-            .get("welcome_timeout_seconds")
-            .and_then(|v| v.as_integer())
-            .map(|v| v as u64)
-            .unwrap_or(300);
-
-        Ok(JoinDetectionConfig {
-            enabled,
-            monitored_rooms,
-            send_welcome,
-            welcome_message,
-            welcome_file,
-            welcome_format,
-            welcome_timeout_seconds,
-        })
-    } else {
-        // No join_detection section, use defaults
-        Ok(JoinDetectionConfig::default())
+        println!("  Join Retry:");
+        println!("    Max Attempts: {}", self.join_retry.max_attempts);
+        println!(
+            "    Initial Delay: {} seconds",
+            self.join_retry.initial_delay_seconds
+        );
+        println!(
+            "    Max Delay: {} seconds",
+            self.join_retry.max_delay_seconds
+        );
+        println!("    Jitter: {}", self.join_retry.jitter);
+        println!("    On Give Up: {}", self.join_retry.on_give_up);
+        println!("  Sync Retry:");
+        println!(
+            "    Initial Delay: {} seconds",
+            self.sync_retry.initial_delay_seconds
+        );
+        println!(
+            "    Max Delay: {} seconds",
+            self.sync_retry.max_delay_seconds
+        );
+        println!("    Jitter: {}", self.sync_retry.jitter);
+        println!("  Housekeeping:");
+        println!("    Enabled: {}", self.housekeeping.enabled);
+        if self.housekeeping.enabled {
+            println!(
+                "    Interval: {} seconds",
+                self.housekeeping.interval_seconds
+            );
+            println!(
+                "    Leave When Alone: {}",
+                self.housekeeping.leave_when_alone
+            );
+            match self.housekeeping.inactive_timeout_seconds {
+                Some(timeout) => println!("    Inactive Timeout: {} seconds", timeout),
+                None => println!("    Inactive Timeout: [never]"),
+            }
+            println!("    Forget Rooms: {}", self.housekeeping.forget_rooms);
+            if !self.housekeeping.exempt_rooms.is_empty() {
+                println!("    Exempt Rooms:");
+                for room in &self.housekeeping.exempt_rooms {
+                    println!("      {}", room);
+                }
+            } else {
+                println!("    Exempt Rooms: [none]");
+            }
+            println!("    Activity File: {}", self.activity_path().display());
+        }
+        println!("  Store:");
+        match self.store_path() {
+            Some(path) => println!("    Path: {}", path.display()),
+            None => println!("    Path: [in memory]"),
+        }
+        println!(
+            "    Passphrase: {}",
+            if self.store.passphrase.is_some() {
+                "[set]"
+            } else {
+                "[not set]"
+            }
+        );
+        println!("  Encryption:");
+        println!("    Enabled: {}", self.encryption.enabled);
+        if self.encryption.enabled {
+            println!(
+                "    Recovery Key: {}",
+                if self.encryption.recovery_key.is_some() {
+                    "[set]"
+                } else {
+                    "[not set]"
+                }
+            );
+        }
+        println!("  Logging:");
+        println!("    Level: {}", self.logging.level);
+        println!("    Format: {}", self.logging.format);
+        println!("    Filter: {}", self.logging.filter);
+        println!("  Metrics:");
+        println!("    Enabled: {}", self.metrics.enabled);
+        if self.metrics.enabled {
+            println!(
+                "    Endpoint: http://{}{}",
+                self.metrics.listen_address, self.metrics.path
+            );
+        }
+        println!("  Health Checks:");
+        println!("    Enabled: {}", self.health.enabled);
+        if self.health.enabled {
+            println!(
+                "    Endpoints: http://{0}/healthz, http://{0}/readyz",
+                self.health.listen_address
+            );
+            println!(
+                "    Max Sync Age: {} seconds",
+                self.health.max_sync_age_seconds
+            );
+        }
+        println!("  Notifications:");
+        println!("    Enabled: {}", self.notifications.enabled);
+        if self.notifications.enabled {
+            println!("    Level: {}", self.notifications.level);
+            println!("    Invites: {}", self.notifications.invites);
+            println!("    Joins: {}", self.notifications.joins);
+            println!(
+                "    Daily Summary: {}",
+                match self.notifications.daily_summary_at {
+                    Some(ref time) => format!("{} UTC", time),
+                    None => "[never]".to_string(),
+                }
+            );
+            println!(
+                "    Batching: every {} seconds, up to {} lines",
+                self.notifications.batch_seconds, self.notifications.max_lines
+            );
+        }
+        println!("  FAQ Answers:");
+        println!("    Enabled: {}", self.faq.enabled);
+        if self.faq.enabled {
+            if self.faq.rooms.is_empty() {
+                println!("    Rooms: [all]");
+            } else {
+                println!("    Rooms: {}", self.faq.rooms.join(", "));
+            }
+            println!("    Cooldown: {} seconds", self.faq.cooldown_seconds);
+            println!("    Threshold: {}", self.faq.threshold);
+            for entry in &self.faq.entries {
+                println!(
+                    "    {}: {} keywords, {} patterns, answered with !{}",
+                    entry.name,
+                    entry.keywords.len(),
+                    entry.patterns.len(),
+                    entry.topic
+                );
+            }
+        }
+        println!("  Usage Statistics:");
+        println!("    Enabled: {}", self.stats.enabled);
+        if self.stats.enabled {
+            println!("    File: {}", self.stats.file);
+            println!("    Privacy: {}", self.stats.privacy);
+        }
+        if self.sources.is_empty() {
+            println!("  Value Sources: [config file and defaults]");
+        } else {
+            println!("  Value Sources (all others from config file or defaults):");
+            for (key, source) in &self.sources {
+                println!("    {}: {}", key, source);
+            }
+        }
     }
 }
 
@@ -332,6 +906,30 @@ pub fn load_welcome_text(file_path: &str) -> Result<String> {
         .with_context(|| format!("Failed to read welcome file '{}'", file_path))
 }
 
+/// Build the message content for a text in the given format.
+pub fn render_message(text: &str, format: &HelpFormat) -> RoomMessageEventContent {
+    match format {
+        HelpFormat::Plain => RoomMessageEventContent::text_plain(text),
+        HelpFormat::Html => RoomMessageEventContent::text_html(text, text),
+        HelpFormat::Markdown => RoomMessageEventContent::text_markdown(text),
+    }
+}
+
+/// Build the text of the welcome message for a user who just joined.
+///
+/// The text from the welcome file, if any, is appended to `welcome_message`,
+/// and the whole message is addressed to the user.
+pub fn welcome_message_text(
+    config: &JoinDetectionConfig,
+    welcome_text: Option<&str>,
+    user_id: &str,
+) -> String {
+    match welcome_text {
+        Some(file_text) => format!("{}: {}\n{}", user_id, config.welcome_message, file_text),
+        None => format!("{}: {}", user_id, config.welcome_message),
+    }
+}
+
 /// Check if a user ID should be ignored based on bot filtering configuration.
 pub fn should_ignore_user(user_id: &str, bot_user_id: &str, config: &BotFilteringConfig) -> bool {
     // Check if it's bot itself
@@ -373,17 +971,51 @@ mod tests {
         // Then all required fields should be parsed correctly and defaults should be applied
         assert_eq!(config.homeserver, "https://matrix.example.com");
         assert_eq!(config.username, "@bot:example.com");
-        assert_eq!(config.access_token, "secret_token");
+        assert_eq!(config.access_token.as_deref(), Some("secret_token"));
+        assert_eq!(config.refresh_token, None);
+        assert_eq!(config.password, None);
+        assert_eq!(config.session_file, "session.json");
         assert_eq!(config.log_file, "bot.log");
+        assert_eq!(config.pid_file, "matrix-bot-help.pid");
+        assert_eq!(config.shutdown_timeout_seconds, 8);
         assert_eq!(config.working_dir, ".");
         assert_eq!(config.help_file, "help.md");
         assert_eq!(config.help_format, HelpFormat::Plain);
+        assert!(config.admins.is_empty());
+        assert_eq!(config.admin_room, None);
+        assert_eq!(config.admin_power_level, 100);
+        assert_eq!(config.ignore_path(), Path::new("./ignored-users.json"));
         // Bot filtering should use defaults when not specified
         assert!(config.bot_filtering.ignore_self);
         assert!(!config.bot_filtering.ignore_bots);
         assert!(config.bot_filtering.ignored_users.is_empty());
     }
 
+    #[test]
+    fn test_relative_working_directory_is_resolved() {
+        // Given a config with a relative working directory and a store
+        let mut config = parse_with(indoc! {"
+            working_directory = \"data\"
+            stats = { enabled = true }
+            store = { path = \"store\" }
+        "})
+        .unwrap();
+
+        // When resolving the working directory, as before daemonizing into it
+        config.resolve_working_dir().unwrap();
+
+        // Then every derived path should be absolute, so that changing into the
+        // directory doesn't nest them as data/data/...
+        let data = std::env::current_dir().unwrap().join("data");
+        assert_eq!(Path::new(&config.working_dir), data);
+        assert_eq!(config.session_path(), data.join("session.json"));
+        assert_eq!(config.ignore_path(), data.join("ignored-users.json"));
+        assert_eq!(config.stats_path(), data.join("stats.json"));
+        assert_eq!(config.activity_path(), data.join("room-activity.json"));
+        assert_eq!(config.pid_path(), data.join("matrix-bot-help.pid"));
+        assert_eq!(config.store_path(), Some(data.join("store")));
+    }
+
     #[test]
     fn test_full_config_parsing() {
         // Given a complete TOML configuration with all optional fields
@@ -391,10 +1023,16 @@ mod tests {
             homeserver = \"https://matrix.example.com\"
             username = \"@bot:example.com\"
             access_token = \"secret_token\"
+            refresh_token = \"refresh_token\"
             log_file = \"/var/log/bot.log\"
+            pid_file = \"/run/matrix-bot-help.pid\"
             working_directory = \"/app\"
             help_file = \"/path/to/help.md\"
             help_format = \"markdown\"
+            admins = [\"@admin:example.com\"]
+            admin_room = \"!admins:example.com\"
+            admin_power_level = 50
+            ignore_file = \"ignored.json\"
 
             [bot_filtering]
             ignore_self = false
@@ -408,11 +1046,17 @@ mod tests {
         // Then all fields should be parsed with their specified values
         assert_eq!(config.homeserver, "https://matrix.example.com");
         assert_eq!(config.username, "@bot:example.com");
-        assert_eq!(config.access_token, "secret_token");
+        assert_eq!(config.access_token.as_deref(), Some("secret_token"));
+        assert_eq!(config.refresh_token.as_deref(), Some("refresh_token"));
         assert_eq!(config.log_file, "/var/log/bot.log");
+        assert_eq!(config.pid_path(), Path::new("/run/matrix-bot-help.pid"));
         assert_eq!(config.working_dir, "/app");
         assert_eq!(config.help_file, "/path/to/help.md");
         assert_eq!(config.help_format, HelpFormat::Markdown);
+        assert_eq!(config.admins, vec!["@admin:example.com".to_string()]);
+        assert_eq!(config.admin_room.as_deref(), Some("!admins:example.com"));
+        assert_eq!(config.admin_power_level, 50);
+        assert_eq!(config.ignore_path(), Path::new("/app/ignored.json"));
         assert!(!config.bot_filtering.ignore_self);
         assert!(config.bot_filtering.ignore_bots);
         assert_eq!(config.bot_filtering.ignored_users.len(), 2);
@@ -824,4 +1468,1014 @@ mod tests {
         // Then the timeout should be parsed correctly
         assert_eq!(config.join_detection.welcome_timeout_seconds, 600);
     }
+
+    #[test]
+    fn test_join_retry_config_parsing() {
+        // Given TOML configurations with and without a join_retry section
+        let custom_toml = indoc! {"
+            homeserver = \"https://matrix.example.com\"
+            username = \"@bot:example.com\"
+            access_token = \"secret_token\"
+            help_file = \"help.md\"
+
+            [join_retry]
+            max_attempts = 4
+            initial_delay_seconds = 5
+            max_delay_seconds = 120
+            jitter = false
+            on_give_up = \"decline\"
+        "};
+
+        let default_toml = indoc! {"
+            homeserver = \"https://matrix.example.com\"
+            username = \"@bot:example.com\"
+            access_token = \"secret_token\"
+            help_file = \"help.md\"
+        "};
+
+        let zero_attempts_toml = indoc! {"
+            homeserver = \"https://matrix.example.com\"
+            username = \"@bot:example.com\"
+            access_token = \"secret_token\"
+            help_file = \"help.md\"
+
+            [join_retry]
+            max_attempts = 0
+        "};
+
+        // When parsing the configurations
+        let custom_config = Config::from_toml(custom_toml).unwrap();
+        let default_config = Config::from_toml(default_toml).unwrap();
+        let zero_attempts_result = Config::from_toml(zero_attempts_toml);
+
+        // Then the custom values and defaults should be applied
+        assert_eq!(
+            custom_config.join_retry,
+            RetryPolicy {
+                max_attempts: 4,
+                initial_delay_seconds: 5,
+                max_delay_seconds: 120,
+                jitter: false,
+                on_give_up: GiveUpAction::Decline,
+            }
+        );
+        assert_eq!(default_config.join_retry, RetryPolicy::default());
+
+        // And zero attempts should be rejected
+        assert!(
+            zero_attempts_result
+                .unwrap_err()
+                .to_string()
+                .contains("max_attempts")
+        );
+    }
+
+    #[test]
+    fn test_housekeeping_config_parsing() {
+        // Given TOML configurations with and without a housekeeping section
+        let custom_toml = indoc! {"
+            homeserver = \"https://matrix.example.com\"
+            username = \"@bot:example.com\"
+            access_token = \"secret_token\"
+            help_file = \"help.md\"
+
+            [housekeeping]
+            enabled = true
+            interval_seconds = 600
+            leave_when_alone = false
+            inactive_timeout_seconds = 2592000
+            forget_rooms = true
+            exempt_rooms = [\"!lobby:example.com\"]
+            activity_file = \"activity.json\"
+        "};
+
+        let default_toml = indoc! {"
+            homeserver = \"https://matrix.example.com\"
+            username = \"@bot:example.com\"
+            access_token = \"secret_token\"
+            help_file = \"help.md\"
+        "};
+
+        // When parsing the configurations
+        let custom_config = Config::from_toml(custom_toml).unwrap();
+        let default_config = Config::from_toml(default_toml).unwrap();
+
+        // Then the custom values should be parsed
+        assert!(custom_config.housekeeping.enabled);
+        assert_eq!(custom_config.housekeeping.interval_seconds, 600);
+        assert!(!custom_config.housekeeping.leave_when_alone);
+        assert_eq!(
+            custom_config.housekeeping.inactive_timeout_seconds,
+            Some(2592000)
+        );
+        assert!(custom_config.housekeeping.forget_rooms);
+        assert_eq!(
+            custom_config.housekeeping.exempt_rooms,
+            vec!["!lobby:example.com".to_string()]
+        );
+        assert_eq!(custom_config.housekeeping.activity_file, "activity.json");
+
+        // And housekeeping should be off by default
+        assert!(!default_config.housekeeping.enabled);
+        assert!(default_config.housekeeping.leave_when_alone);
+        assert_eq!(default_config.housekeeping.inactive_timeout_seconds, None);
+        assert!(!default_config.housekeeping.forget_rooms);
+    }
+
+    #[test]
+    fn test_password_login_config_parsing() {
+        // Given a TOML configuration with a password instead of an access token
+        let toml_str = indoc! {"
+            homeserver = \"https://matrix.example.com\"
+            username = \"@bot:example.com\"
+            password = \"hunter2\"
+            working_directory = \"/app/data\"
+            session_file = \"bot-session.json\"
+            help_file = \"help.md\"
+        "};
+
+        // When parsing the TOML configuration
+        let config = Config::from_toml(toml_str).unwrap();
+
+        // Then the password and session location should be parsed
+        assert_eq!(config.access_token, None);
+        assert_eq!(config.password.as_deref(), Some("hunter2"));
+        assert_eq!(
+            config.session_path(),
+            PathBuf::from("/app/data/bot-session.json")
+        );
+    }
+
+    #[test]
+    fn test_encryption_config_parsing() {
+        // Given TOML configurations with and without an encryption section
+        let enabled_toml = indoc! {"
+            homeserver = \"https://matrix.example.com\"
+            username = \"@bot:example.com\"
+            access_token = \"secret_token\"
+            device_id = \"BOTDEVICE\"
+            working_directory = \"/app/data\"
+            help_file = \"help.md\"
+
+            [encryption]
+            enabled = true
+            recovery_key = \"EsTc abcd efgh\"
+        "};
+
+        let default_toml = indoc! {"
+            homeserver = \"https://matrix.example.com\"
+            username = \"@bot:example.com\"
+            access_token = \"secret_token\"
+            help_file = \"help.md\"
+        "};
+
+        // When parsing the configurations
+        let enabled_config = Config::from_toml(enabled_toml).unwrap();
+        let default_config = Config::from_toml(default_toml).unwrap();
+
+        // Then the encryption settings should be parsed
+        assert_eq!(enabled_config.device_id, "BOTDEVICE");
+        assert!(enabled_config.encryption.enabled);
+        assert_eq!(
+            enabled_config.store_path(),
+            Some(PathBuf::from("/app/data/store"))
+        );
+        assert_eq!(
+            enabled_config.encryption.recovery_key.as_deref(),
+            Some("EsTc abcd efgh")
+        );
+
+        // And encryption should be off by default
+        assert_eq!(default_config.device_id, "matrix-bot-help");
+        assert!(!default_config.encryption.enabled);
+        assert_eq!(default_config.encryption.recovery_key, None);
+    }
+
+    #[test]
+    fn test_store_config_parsing() {
+        // Given TOML configurations with and without a store section
+        let store_toml = indoc! {"
+            homeserver = \"https://matrix.example.com\"
+            username = \"@bot:example.com\"
+            access_token = \"secret_token\"
+            working_directory = \"/app/data\"
+            help_file = \"help.md\"
+
+            [store]
+            path = \"state\"
+            passphrase = \"store secret\"
+        "};
+
+        let default_toml = indoc! {"
+            homeserver = \"https://matrix.example.com\"
+            username = \"@bot:example.com\"
+            access_token = \"secret_token\"
+            help_file = \"help.md\"
+        "};
+
+        // When parsing the configurations
+        let store_config = Config::from_toml(store_toml).unwrap();
+        let default_config = Config::from_toml(default_toml).unwrap();
+
+        // Then the store should be on disk only when configured
+        assert_eq!(
+            store_config.store_path(),
+            Some(PathBuf::from("/app/data/state"))
+        );
+        assert_eq!(
+            store_config.store.passphrase.as_deref(),
+            Some("store secret")
+        );
+        assert_eq!(default_config.store_path(), None);
+        assert_eq!(default_config.store.passphrase, None);
+    }
+
+    /// Parse a config made of the required fields followed by `extra`.
+    fn parse_with(extra: &str) -> Result<Config> {
+        let toml_str = format!(
+            "{}{}",
+            indoc! {"
+                homeserver = \"https://matrix.example.com\"
+                username = \"@bot:example.com\"
+                access_token = \"secret_token\"
+                help_file = \"help.md\"
+            "},
+            extra
+        );
+        Config::from_toml(&toml_str)
+    }
+
+    #[test]
+    fn test_example_config_is_accepted() {
+        // Given the example configuration shipped with the bot
+        let toml_str = include_str!("../bot.toml.example");
+
+        // When parsing it
+        let result = Config::from_toml(toml_str);
+
+        // Then it should parse without errors
+        assert!(result.is_ok(), "{:?}", result.err());
+    }
+
+    #[test]
+    fn test_unknown_top_level_key_error() {
+        // Given a configuration with a misspelled top-level key
+        let result = parse_with("help_fromat = \"markdown\"\n");
+
+        // When parsing it
+        let message = result.unwrap_err().to_string();
+
+        // Then the error should name the key and its position
+        assert!(
+            message.contains("unknown field `help_fromat`"),
+            "{}",
+            message
+        );
+        assert!(message.contains("line 5, column 1"), "{}", message);
+    }
+
+    #[test]
+    fn test_unknown_section_key_error() {
+        // Given a configuration with a misspelled key in a section
+        let result = parse_with(indoc! {"
+
+            [bot_filtering]
+            ignore_bot = true
+        "});
+
+        // When parsing it
+        let message = result.unwrap_err().to_string();
+
+        // Then the error should name the key and its position
+        assert!(
+            message.contains("unknown field `ignore_bot`"),
+            "{}",
+            message
+        );
+        assert!(message.contains("line 7, column 1"), "{}", message);
+    }
+
+    #[test]
+    fn test_unknown_welcome_key_error() {
+        // Given a configuration with a misspelled welcome_file key
+        let result = parse_with(indoc! {"
+
+            [join_detection]
+            send_welcome = true
+            welcom_file = \"welcome.md\"
+        "});
+
+        // When parsing it
+        let message = result.unwrap_err().to_string();
+
+        // Then the error should name the key and its position
+        assert!(
+            message.contains("unknown field `welcom_file`"),
+            "{}",
+            message
+        );
+        assert!(message.contains("line 8, column 1"), "{}", message);
+    }
+
+    #[test]
+    fn test_unknown_section_error() {
+        // Given a configuration with a misspelled section name
+        let result = parse_with(indoc! {"
+
+            [bot_filter]
+            ignore_self = true
+        "});
+
+        // When parsing it
+        let message = result.unwrap_err().to_string();
+
+        // Then the error should name the section
+        assert!(
+            message.contains("unknown field `bot_filter`"),
+            "{}",
+            message
+        );
+    }
+
+    #[test]
+    fn test_wrong_type_bool_error() {
+        // Given a configuration with a string where a bool is expected
+        let result = parse_with(indoc! {"
+
+            [bot_filtering]
+            ignore_self = \"false\"
+        "});
+
+        // When parsing it
+        let message = result.unwrap_err().to_string();
+
+        // Then the error should describe the type mismatch and its position
+        assert!(message.contains("invalid type: string"), "{}", message);
+        assert!(message.contains("expected a boolean"), "{}", message);
+        assert!(message.contains("line 7, column 15"), "{}", message);
+    }
+
+    #[test]
+    fn test_wrong_type_integer_error() {
+        // Given a configuration with a string where an integer is expected
+        let result = parse_with(indoc! {"
+
+            [join_detection]
+            welcome_timeout_seconds = \"600\"
+        "});
+
+        // When parsing it
+        let message = result.unwrap_err().to_string();
+
+        // Then the error should describe the type mismatch and its position
+        assert!(message.contains("invalid type: string"), "{}", message);
+        assert!(message.contains("line 7"), "{}", message);
+    }
+
+    #[test]
+    fn test_wrong_type_list_error() {
+        // Given a configuration with a single string where a list is expected
+        let result = parse_with("admins = \"@admin:example.com\"\n");
+
+        // When parsing it
+        let message = result.unwrap_err().to_string();
+
+        // Then the error should describe the type mismatch and its position
+        assert!(message.contains("invalid type: string"), "{}", message);
+        assert!(message.contains("expected a sequence"), "{}", message);
+        assert!(message.contains("line 5"), "{}", message);
+    }
+
+    #[test]
+    fn test_negative_integer_error() {
+        // Given a configuration with a negative attempt count
+        let result = parse_with(indoc! {"
+
+            [join_retry]
+            max_attempts = -1
+        "});
+
+        // When parsing it
+        let message = result.unwrap_err().to_string();
+
+        // Then the error should reject the value with its position
+        assert!(message.contains("invalid value"), "{}", message);
+        assert!(message.contains("line 7"), "{}", message);
+    }
+
+    #[test]
+    fn test_invalid_enum_value_error() {
+        // Given configurations with invalid welcome format and give up action
+        let format_result = parse_with(indoc! {"
+
+            [join_detection]
+            welcome_format = \"rtf\"
+        "});
+        let give_up_result = parse_with(indoc! {"
+
+            [join_retry]
+            on_give_up = \"panic\"
+        "});
+
+        // When parsing them
+        let format_message = format_result.unwrap_err().to_string();
+        let give_up_message = give_up_result.unwrap_err().to_string();
+
+        // Then the errors should list the valid options and the position
+        assert!(
+            format_message.contains("Invalid help format 'rtf'"),
+            "{}",
+            format_message
+        );
+        assert!(format_message.contains("line 7"), "{}", format_message);
+        assert!(
+            give_up_message.contains("Invalid give up action 'panic'"),
+            "{}",
+            give_up_message
+        );
+        assert!(give_up_message.contains("line 7"), "{}", give_up_message);
+    }
+
+    fn env(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_env_overrides_config_values() {
+        // Given a config without an access token and overriding environment variables
+        let toml_str = indoc! {"
+            homeserver = \"https://matrix.example.com\"
+            username = \"@bot:example.com\"
+            help_file = \"help.md\"
+
+            [bot_filtering]
+            ignore_bots = false
+        "};
+        let vars = env(&[
+            ("MATRIX_BOT_HELP_ACCESS_TOKEN", "syt_from_env"),
+            ("MATRIX_BOT_HELP_BOT_FILTERING__IGNORE_BOTS", "true"),
+            (
+                "MATRIX_BOT_HELP_HOUSEKEEPING__INACTIVE_TIMEOUT_SECONDS",
+                "86400",
+            ),
+        ]);
+
+        // When parsing the config with the environment
+        let config = Config::from_toml_with_env(toml_str, vars).unwrap();
+
+        // Then the environment should win and its sources be recorded
+        assert_eq!(config.access_token, Some("syt_from_env".to_string()));
+        assert!(config.bot_filtering.ignore_bots);
+        assert_eq!(config.housekeeping.inactive_timeout_seconds, Some(86400));
+        assert_eq!(
+            config.sources.get("access_token"),
+            Some(&ValueSource::Env(
+                "MATRIX_BOT_HELP_ACCESS_TOKEN".to_string()
+            ))
+        );
+        assert!(!config.sources.contains_key("homeserver"));
+    }
+
+    #[test]
+    fn test_env_override_errors() {
+        // Given overrides with an unknown key and a wrongly typed value
+        let unknown = parse_with_env(&[("MATRIX_BOT_HELP_ACESS_TOKEN", "x")]);
+        let wrong_type = parse_with_env(&[("MATRIX_BOT_HELP_JOIN_RETRY__JITTER", "sometimes")]);
+
+        // When parsing the config
+        let unknown_message = unknown.unwrap_err().to_string();
+        let wrong_type_message = wrong_type.unwrap_err().to_string();
+
+        // Then both should be reported as invalid overrides
+        assert!(
+            unknown_message.contains("Invalid environment override")
+                && unknown_message.contains("acess_token"),
+            "{}",
+            unknown_message
+        );
+        assert!(
+            wrong_type_message.contains("Invalid environment override"),
+            "{}",
+            wrong_type_message
+        );
+    }
+
+    #[test]
+    fn test_env_override_unquoted_string_values() {
+        // Given unquoted values that look like numbers or booleans for string keys
+        let config = parse_with_env(&[
+            ("MATRIX_BOT_HELP_ACCESS_TOKEN", "123456"),
+            ("MATRIX_BOT_HELP_DEVICE_ID", "true"),
+            ("MATRIX_BOT_HELP_JOIN_RETRY__MAX_ATTEMPTS", "7"),
+        ]);
+
+        // When parsing the config
+        let config = config.unwrap();
+
+        // Then string keys should get the raw text and other keys keep their types
+        assert_eq!(config.access_token, Some("123456".to_string()));
+        assert_eq!(config.device_id, "true");
+        assert_eq!(config.join_retry.max_attempts, 7);
+    }
+
+    fn parse_with_env(pairs: &[(&str, &str)]) -> Result<Config> {
+        let toml_str = indoc! {"
+            homeserver = \"https://matrix.example.com\"
+            username = \"@bot:example.com\"
+            access_token = \"secret_token\"
+            help_file = \"help.md\"
+        "};
+        Config::from_toml_with_env(toml_str, env(pairs))
+    }
+
+    #[test]
+    fn test_secret_files() {
+        // Given secrets stored in files
+        fs::write("test_access_token.secret", "syt_from_file\n").unwrap();
+        fs::write("test_recovery_key.secret", "EsTc abcd\n").unwrap();
+        let toml_str = indoc! {"
+            homeserver = \"https://matrix.example.com\"
+            username = \"@bot:example.com\"
+            access_token_file = \"test_access_token.secret\"
+            help_file = \"help.md\"
+
+            [encryption]
+            enabled = true
+            recovery_key_file = \"test_recovery_key.secret\"
+        "};
+
+        // When parsing the config
+        let config = Config::from_toml(toml_str).unwrap();
+
+        // Then the secrets should be read from the files
+        assert_eq!(config.access_token, Some("syt_from_file".to_string()));
+        assert_eq!(
+            config.encryption.recovery_key,
+            Some("EsTc abcd".to_string())
+        );
+        assert_eq!(
+            config.sources.get("encryption.recovery_key"),
+            Some(&ValueSource::SecretFile(
+                "test_recovery_key.secret".to_string()
+            ))
+        );
+
+        // Clean up
+        fs::remove_file("test_access_token.secret").unwrap();
+        fs::remove_file("test_recovery_key.secret").unwrap();
+    }
+
+    #[test]
+    fn test_env_secret_replaces_secret_file() {
+        // Given an access token file in the config file and a token in the environment
+        fs::write("test_env_access_token.secret", "syt_from_file\n").unwrap();
+        let toml_str = indoc! {"
+            homeserver = \"https://matrix.example.com\"
+            username = \"@bot:example.com\"
+            access_token_file = \"test_env_access_token.secret\"
+            help_file = \"help.md\"
+        "};
+        let vars = env(&[("MATRIX_BOT_HELP_ACCESS_TOKEN", "abc")]);
+
+        // When parsing the config with the environment
+        let config = Config::from_toml_with_env(toml_str, vars).unwrap();
+
+        // Then the environment value should replace the file
+        assert_eq!(config.access_token, Some("abc".to_string()));
+        assert_eq!(
+            config.sources.get("access_token"),
+            Some(&ValueSource::Env(
+                "MATRIX_BOT_HELP_ACCESS_TOKEN".to_string()
+            ))
+        );
+
+        // Clean up
+        fs::remove_file("test_env_access_token.secret").unwrap();
+    }
+
+    #[test]
+    fn test_secret_file_conflicts_and_missing_files() {
+        // Given a secret set both inline and as a file, in the config file and in
+        // the environment, and a missing secret file
+        let both = parse_with("password = \"x\"\npassword_file = \"password.secret\"\n");
+        let both_env = parse_with_env(&[
+            ("MATRIX_BOT_HELP_PASSWORD", "x"),
+            ("MATRIX_BOT_HELP_PASSWORD_FILE", "password.secret"),
+        ]);
+        let missing = parse_with_env(&[(
+            "MATRIX_BOT_HELP_STORE__PASSPHRASE_FILE",
+            "/nonexistent/passphrase",
+        )]);
+
+        // When parsing the configs
+        let both_message = both.unwrap_err().to_string();
+        let both_env_message = both_env.unwrap_err().to_string();
+        let missing_message = missing.unwrap_err().to_string();
+
+        // Then all should be rejected with a clear message
+        assert!(
+            both_message.contains("Only one of 'password' and 'password_file'"),
+            "{}",
+            both_message
+        );
+        assert!(
+            both_env_message.contains("Only one of 'password' and 'password_file'"),
+            "{}",
+            both_env_message
+        );
+        assert!(
+            missing_message.contains("Failed to read secret file '/nonexistent/passphrase'"),
+            "{}",
+            missing_message
+        );
+    }
+
+    #[test]
+    fn test_welcome_message_text() {
+        // Given join detection with a welcome message
+        let config = JoinDetectionConfig {
+            welcome_message: "Welcome!".to_string(),
+            ..JoinDetectionConfig::default()
+        };
+
+        // When building the message with and without text from a welcome file
+        let without_file = welcome_message_text(&config, None, "@alice:example.com");
+        let with_file =
+            welcome_message_text(&config, Some("Read the **rules**."), "@alice:example.com");
+
+        // Then the user should be addressed and the file text appended
+        assert_eq!(without_file, "@alice:example.com: Welcome!");
+        assert_eq!(
+            with_file,
+            "@alice:example.com: Welcome!\nRead the **rules**."
+        );
+    }
+
+    #[test]
+    fn test_sync_retry_config_parsing() {
+        // Given configurations with a custom, a default and a zero-delay sync_retry section
+        let custom = parse_with(indoc! {"
+
+            [sync_retry]
+            initial_delay_seconds = 5
+            max_delay_seconds = 60
+            jitter = false
+        "});
+        let default = parse_with("");
+        let zero_delay = parse_with(indoc! {"
+
+            [sync_retry]
+            initial_delay_seconds = 0
+        "});
+
+        // When parsing them
+        // Then custom values and defaults should be applied, and no delay rejected
+        assert_eq!(
+            custom.unwrap().sync_retry,
+            ReconnectPolicy {
+                initial_delay_seconds: 5,
+                max_delay_seconds: 60,
+                jitter: false,
+            }
+        );
+        assert_eq!(default.unwrap().sync_retry, ReconnectPolicy::default());
+        assert!(
+            zero_delay
+                .unwrap_err()
+                .to_string()
+                .contains("'sync_retry.initial_delay_seconds' must be at least 1")
+        );
+    }
+
+    #[test]
+    fn test_logging_config_parsing() {
+        // Given a JSON logging section, no section, an environment override and a bad format
+        let json = parse_with(indoc! {"
+
+            [logging]
+            level = \"debug\"
+            format = \"json\"
+            filter = \"matrix_sdk=error\"
+        "});
+        let default = parse_with("");
+        let overridden = parse_with_env(&[("MATRIX_BOT_HELP_LOGGING__LEVEL", "warn")]);
+        let bad_format = parse_with(indoc! {"
+
+            [logging]
+            format = \"xml\"
+        "});
+
+        // When parsing them
+        // Then the values, defaults and override should be applied, and the bad format rejected
+        assert_eq!(
+            json.unwrap().logging,
+            LoggingConfig {
+                level: "debug".to_string(),
+                format: LogFormat::Json,
+                filter: "matrix_sdk=error".to_string(),
+            }
+        );
+        assert_eq!(default.unwrap().logging, LoggingConfig::default());
+        assert_eq!(overridden.unwrap().logging.level, "warn");
+        assert!(
+            bad_format
+                .unwrap_err()
+                .to_string()
+                .contains("Invalid log format 'xml'")
+        );
+    }
+
+    #[test]
+    fn test_log_rotation_config_parsing() {
+        // Given a log_rotation section, no section and a zero size
+        let custom = parse_with(indoc! {"
+
+            [log_rotation]
+            max_size_mb = 50
+            interval_seconds = 86400
+            keep_files = 3
+        "});
+        let default = parse_with("");
+        let zero_size = parse_with(indoc! {"
+
+            [log_rotation]
+            max_size_mb = 0
+        "});
+
+        // When parsing them
+        // Then custom values and defaults should be applied, and a zero size rejected
+        assert_eq!(
+            custom.unwrap().log_rotation,
+            LogRotationConfig {
+                max_size_mb: Some(50),
+                interval_seconds: Some(86400),
+                keep_files: 3,
+            }
+        );
+        assert_eq!(default.unwrap().log_rotation, LogRotationConfig::default());
+        assert!(
+            zero_size
+                .unwrap_err()
+                .to_string()
+                .contains("'log_rotation.max_size_mb' must be at least 1")
+        );
+    }
+
+    #[test]
+    fn test_metrics_config_parsing() {
+        // Given an enabled metrics section, no section and an invalid address
+        let enabled = parse_with(indoc! {"
+
+            [metrics]
+            enabled = true
+            listen_address = \"0.0.0.0:9184\"
+        "});
+        let default = parse_with("");
+        let invalid = parse_with(indoc! {"
+
+            [metrics]
+            listen_address = \"localhost\"
+        "});
+
+        // When parsing them
+        // Then the values and defaults should be applied, and the address rejected
+        assert_eq!(
+            enabled.unwrap().metrics,
+            MetricsConfig {
+                enabled: true,
+                listen_address: "0.0.0.0:9184".to_string(),
+                path: "/metrics".to_string(),
+            }
+        );
+        assert_eq!(default.unwrap().metrics, MetricsConfig::default());
+        assert!(
+            invalid
+                .unwrap_err()
+                .to_string()
+                .contains("'metrics.listen_address' must be an IP address and port")
+        );
+    }
+
+    #[test]
+    fn test_health_config_parsing() {
+        // Given an enabled health section, no section, a zero age and a path clash
+        let enabled = parse_with(indoc! {"
+
+            [health]
+            enabled = true
+            listen_address = \"0.0.0.0:9185\"
+            max_sync_age_seconds = 300
+        "});
+        let default = parse_with("");
+        let zero_age = parse_with(indoc! {"
+
+            [health]
+            max_sync_age_seconds = 0
+        "});
+        let clash = parse_with(indoc! {"
+
+            [metrics]
+            enabled = true
+            listen_address = \"127.0.0.1:9185\"
+            path = \"/healthz\"
+
+            [health]
+            enabled = true
+        "});
+
+        // When parsing them
+        // Then the values and defaults should be applied, and the others rejected
+        assert_eq!(
+            enabled.unwrap().health,
+            HealthConfig {
+                enabled: true,
+                listen_address: "0.0.0.0:9185".to_string(),
+                max_sync_age_seconds: 300,
+            }
+        );
+        assert_eq!(default.unwrap().health, HealthConfig::default());
+        assert!(
+            zero_age
+                .unwrap_err()
+                .to_string()
+                .contains("'health.max_sync_age_seconds' must be at least 1")
+        );
+        assert!(
+            clash
+                .unwrap_err()
+                .to_string()
+                .contains("'metrics.path' must not be /healthz or /readyz")
+        );
+    }
+
+    #[test]
+    fn test_notifications_config_parsing() {
+        // Given enabled notifications, ones without an admin room and an invalid summary time
+        let enabled = parse_with(indoc! {"
+            admin_room = \"!admins:example.com\"
+
+            [notifications]
+            enabled = true
+            level = \"error\"
+            joins = false
+            daily_summary_at = \"08:30\"
+        "});
+        let no_room = parse_with(indoc! {"
+
+            [notifications]
+            enabled = true
+        "});
+        let bad_time = parse_with(indoc! {"
+
+            [notifications]
+            daily_summary_at = \"8.30\"
+        "});
+
+        // When parsing them
+        // Then the values and defaults should be applied, and the others rejected
+        assert_eq!(
+            enabled.unwrap().notifications,
+            NotificationsConfig {
+                enabled: true,
+                level: NotificationLevel::Error,
+                joins: false,
+                daily_summary_at: Some("08:30".to_string()),
+                ..NotificationsConfig::default()
+            }
+        );
+        assert!(
+            no_room
+                .unwrap_err()
+                .to_string()
+                .contains("'notifications.enabled' needs 'admin_room'")
+        );
+        assert!(
+            format!("{:#}", bad_time.unwrap_err())
+                .contains("Invalid time '8.30', expected HH:MM like 09:00")
+        );
+    }
+
+    #[test]
+    fn test_stats_config_parsing() {
+        // Given aggregated statistics in a custom file, and an unknown privacy mode
+        let aggregated = parse_with(indoc! {"
+
+            [stats]
+            enabled = true
+            file = \"usage.json\"
+            privacy = \"aggregated\"
+        "});
+        let unknown = parse_with(indoc! {"
+
+            [stats]
+            privacy = \"anonymous\"
+        "});
+
+        // When parsing them
+        // Then the values should be applied, and the unknown mode rejected
+        let config = aggregated.unwrap();
+        assert_eq!(
+            config.stats,
+            StatsConfig {
+                enabled: true,
+                file: "usage.json".to_string(),
+                privacy: StatsPrivacy::Aggregated,
+            }
+        );
+        assert_eq!(config.stats_path(), Path::new("./usage.json"));
+        assert!(format!("{:#}", unknown.unwrap_err()).contains(
+            "Invalid stats privacy 'anonymous'. Valid options are: detailed, aggregated"
+        ));
+    }
+
+    #[test]
+    fn test_commands_config_parsing() {
+        // Given two commands, one from a file and one inline, and a command without a response
+        let valid = parse_with(indoc! {"
+
+            [[commands]]
+            name = \"rules\"
+            aliases = [\"r\"]
+            description = \"Room rules\"
+            file = \"rules.md\"
+            format = \"markdown\"
+            delivery = \"direct\"
+
+            [[commands]]
+            name = \"oncall\"
+            text = \"Ask @oncall:example.com\"
+        "});
+        let no_response = parse_with(indoc! {"
+
+            [[commands]]
+            name = \"faq\"
+        "});
+
+        // When parsing them
+        // Then the values and defaults should be applied, and the command without a response rejected
+        let commands = valid.unwrap().commands;
+        assert_eq!(commands.len(), 2);
+        assert_eq!(commands[0].aliases, vec!["r".to_string()]);
+        assert_eq!(commands[0].file.as_deref(), Some("rules.md"));
+        assert_eq!(commands[0].format, HelpFormat::Markdown);
+        assert_eq!(commands[0].delivery, Delivery::Direct);
+        assert_eq!(commands[1].text.as_deref(), Some("Ask @oncall:example.com"));
+        assert_eq!(commands[1].format, HelpFormat::Plain);
+        assert_eq!(commands[1].delivery, Delivery::Room);
+        assert_eq!(
+            no_response.unwrap_err().to_string(),
+            "Command 'faq' needs either 'file' or 'text'"
+        );
+    }
+
+    #[test]
+    fn test_faq_config_parsing() {
+        // Given FAQ entries linked to a custom command and to the help text
+        let config = parse_with(indoc! {"
+
+            [[commands]]
+            name = \"password\"
+            text = \"Reset it at https://example.com/reset\"
+
+            [faq]
+            enabled = true
+            rooms = [\"!support:example.com\"]
+            threshold = 0.5
+
+            [[faq.entries]]
+            name = \"password-reset\"
+            keywords = [\"reset\", \"password\"]
+            topic = \"password\"
+
+            [[faq.entries]]
+            name = \"getting-started\"
+            patterns = [\"(?i)how do i (start|begin)\"]
+            topic = \"help\"
+        "});
+        let unknown_topic = parse_with(indoc! {"
+
+            [[faq.entries]]
+            name = \"vpn\"
+            keywords = [\"vpn\"]
+            topic = \"vpn\"
+        "});
+
+        // When parsing them
+        // Then the entries and defaults should be applied, and unknown topics rejected
+        let faq = config.unwrap().faq;
+        assert!(faq.enabled);
+        assert_eq!(faq.rooms, vec!["!support:example.com".to_string()]);
+        assert_eq!(faq.threshold, 0.5);
+        assert_eq!(faq.cooldown_seconds, 600);
+        assert_eq!(faq.entries.len(), 2);
+        assert_eq!(faq.entries[0].keywords, vec!["reset", "password"]);
+        assert_eq!(faq.entries[1].topic, "help");
+        assert_eq!(
+            unknown_topic.unwrap_err().to_string(),
+            "FAQ 'vpn' links to unknown topic 'vpn', expected 'help' or a command name"
+        );
+    }
 }
@@ -0,0 +1,234 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tracing::info;
+
+/// Configuration for rotating `log_file` in daemon mode.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogRotationConfig {
+    /// Rotate once the log file is larger than this many megabytes (None = never)
+    pub max_size_mb: Option<u64>,
+    /// Rotate once the log file has been written to for this many seconds (None = never)
+    pub interval_seconds: Option<u64>,
+    /// Number of rotated files to keep, `<log_file>.1` being the newest
+    pub keep_files: usize,
+}
+
+impl Default for LogRotationConfig {
+    fn default() -> Self {
+        Self {
+            max_size_mb: None,
+            interval_seconds: None,
+            keep_files: 5,
+        }
+    }
+}
+
+impl LogRotationConfig {
+    /// Whether a log file of `size` bytes that was started `age` ago is due for rotation.
+    pub fn is_due(&self, size: u64, age: Duration) -> bool {
+        let too_large = self
+            .max_size_mb
+            .is_some_and(|max_size_mb| size > max_size_mb.saturating_mul(1024 * 1024));
+        let too_old = self
+            .interval_seconds
+            .is_some_and(|interval| age >= Duration::from_secs(interval));
+        too_large || too_old
+    }
+}
+
+/// Path of the `n`th rotated file, e.g. `bot.log.1`.
+fn rotated_path(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+/// Rename `path` to `<path>.1`, shifting older files up by one.
+///
+/// Only `keep` rotated files are kept, with `keep = 0` the file is removed.
+pub fn rotate_files(path: &Path, keep: usize) -> Result<()> {
+    if keep == 0 {
+        return fs::remove_file(path)
+            .with_context(|| format!("Failed to remove log file '{}'", path.display()));
+    }
+    for n in (1..keep).rev() {
+        let from = rotated_path(path, n);
+        match fs::rename(&from, rotated_path(path, n + 1)) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to rotate '{}'", from.display()));
+            }
+        }
+    }
+    fs::rename(path, rotated_path(path, 1))
+        .with_context(|| format!("Failed to rotate log file '{}'", path.display()))
+}
+
+/// Point stdout and stderr at `file`.
+fn redirect_output(file: &File) -> Result<()> {
+    // Don't let buffered output of the old file end up in the new one
+    let _ = std::io::stdout().flush();
+    for fd in [libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+        // SAFETY: `file` is borrowed for the whole call, so its descriptor stays open
+        if unsafe { libc::dup2(file.as_raw_fd(), fd) } == -1 {
+            return Err(std::io::Error::last_os_error())
+                .context("Failed to redirect output to the log file");
+        }
+    }
+    Ok(())
+}
+
+/// The log file that a daemon's stdout and stderr are written to.
+#[derive(Debug)]
+pub struct LogFile {
+    path: PathBuf,
+    rotation: LogRotationConfig,
+    /// Device and inode of the open file, to notice when it was moved away
+    id: (u64, u64),
+    opened: Instant,
+}
+
+impl LogFile {
+    /// Take over the log file at `path`, which stdout and stderr already point at.
+    ///
+    /// `path` should be absolute, since a daemon changes its working directory.
+    pub fn new(path: PathBuf, rotation: LogRotationConfig) -> Result<Self> {
+        let metadata = fs::metadata(&path)
+            .with_context(|| format!("Failed to read log file '{}'", path.display()))?;
+        Ok(Self {
+            path,
+            rotation,
+            id: (metadata.dev(), metadata.ino()),
+            opened: Instant::now(),
+        })
+    }
+
+    /// Whether the file at the configured path is still the one being written to.
+    fn is_current(&self) -> bool {
+        fs::metadata(&self.path)
+            .map(|metadata| (metadata.dev(), metadata.ino()) == self.id)
+            .unwrap_or(false)
+    }
+
+    /// Open the file at the configured path, creating it if needed, and write to it.
+    pub fn reopen(&mut self) -> Result<()> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .with_context(|| format!("Failed to open log file '{}'", self.path.display()))?;
+        redirect_output(&file)?;
+        let metadata = file.metadata()?;
+        self.id = (metadata.dev(), metadata.ino());
+        self.opened = Instant::now();
+        Ok(())
+    }
+
+    /// Rotate the log file and continue in a new one.
+    pub fn rotate(&mut self) -> Result<()> {
+        info!(path = %self.path.display(), "Rotating log file");
+        rotate_files(&self.path, self.rotation.keep_files)?;
+        self.reopen()?;
+        info!(path = %self.path.display(), "Rotated log file");
+        Ok(())
+    }
+
+    /// Rotate the log file if it is due according to the configured size and interval.
+    pub fn rotate_if_due(&mut self) -> Result<()> {
+        // A file moved away by logrotate is left alone until it sends SIGUSR1
+        if !self.is_current() {
+            return Ok(());
+        }
+        let size = fs::metadata(&self.path)
+            .map(|metadata| metadata.len())
+            .with_context(|| format!("Failed to read log file '{}'", self.path.display()))?;
+        if self.rotation.is_due(size, self.opened.elapsed()) {
+            self.rotate()?;
+        }
+        Ok(())
+    }
+
+    /// Handle a request to rotate, as sent with SIGUSR1.
+    ///
+    /// If the file was already moved away, e.g. by logrotate, it is only
+    /// reopened at the configured path, so nothing is rotated twice.
+    pub fn rotate_or_reopen(&mut self) -> Result<()> {
+        if self.is_current() {
+            self.rotate()
+        } else {
+            self.reopen()?;
+            info!(path = %self.path.display(), "Reopened log file");
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rotation_is_due() {
+        // Given rotation by size, by a size too large to count in bytes, by time and not at all
+        let by_size = LogRotationConfig {
+            max_size_mb: Some(1),
+            ..LogRotationConfig::default()
+        };
+        let by_time = LogRotationConfig {
+            interval_seconds: Some(86400),
+            ..LogRotationConfig::default()
+        };
+        let huge = LogRotationConfig {
+            max_size_mb: Some(u64::MAX),
+            ..LogRotationConfig::default()
+        };
+        let never = LogRotationConfig::default();
+        let hour = Duration::from_secs(3600);
+        let week = Duration::from_secs(7 * 86400);
+
+        // When checking small, large, new and old files
+        // Then only the configured limits should trigger a rotation
+        assert!(!by_size.is_due(1024 * 1024, week));
+        assert!(by_size.is_due(1024 * 1024 + 1, hour));
+        assert!(!by_time.is_due(u64::MAX, hour));
+        assert!(by_time.is_due(0, week));
+        assert!(!huge.is_due(u64::MAX, week));
+        assert!(!never.is_due(u64::MAX, week));
+    }
+
+    #[test]
+    fn test_rotate_files_keeps_newest() {
+        // Given a log file and two rotated files, keeping two
+        let path = Path::new("test_rotate.log");
+        fs::write(path, "current").unwrap();
+        fs::write(rotated_path(path, 1), "previous").unwrap();
+        fs::write(rotated_path(path, 2), "oldest").unwrap();
+
+        // When rotating
+        rotate_files(path, 2).unwrap();
+
+        // Then the files should shift up and the oldest be dropped
+        assert!(!path.exists());
+        assert_eq!(
+            fs::read_to_string(rotated_path(path, 1)).unwrap(),
+            "current"
+        );
+        assert_eq!(
+            fs::read_to_string(rotated_path(path, 2)).unwrap(),
+            "previous"
+        );
+        assert!(!rotated_path(path, 3).exists());
+
+        // Clean up
+        fs::remove_file(rotated_path(path, 1)).unwrap();
+        fs::remove_file(rotated_path(path, 2)).unwrap();
+    }
+}
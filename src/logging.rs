@@ -0,0 +1,186 @@
+use crate::notifications::Notifications;
+use anyhow::{Result, anyhow};
+use serde::{Deserialize, Deserializer};
+use std::io::IsTerminal;
+use std::str::FromStr;
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer};
+
+/// Environment variable that replaces the configured log filter when set.
+pub const LOG_FILTER_ENV: &str = "RUST_LOG";
+
+/// Output format of log lines.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum LogFormat {
+    /// Readable lines with timestamp, level, span fields and message
+    #[default]
+    Human,
+    /// One JSON object per line, for log aggregation
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "human" | "text" => Ok(LogFormat::Human),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(anyhow!(
+                "Invalid log format '{}'. Valid options are: human, json",
+                s
+            )),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for LogFormat {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        LogFormat::from_str(&s).map_err(serde::de::Error::custom)
+    }
+}
+
+impl std::fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LogFormat::Human => write!(f, "human"),
+            LogFormat::Json => write!(f, "json"),
+        }
+    }
+}
+
+/// Configuration for log output.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    /// Level of everything not matched by `filter` (off, error, warn, info, debug, trace)
+    pub level: String,
+    /// Output format (human, json)
+    pub format: LogFormat,
+    /// Per-target levels in `tracing` filter syntax, e.g. `matrix_sdk=warn`
+    pub filter: String,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            level: "info".to_string(),
+            format: LogFormat::Human,
+            // The SDK logs every request at info level
+            filter: "matrix_sdk=warn".to_string(),
+        }
+    }
+}
+
+impl LoggingConfig {
+    /// Build the log filter from the level and per-target filter.
+    pub fn env_filter(&self) -> Result<EnvFilter> {
+        let level = LevelFilter::from_str(&self.level).map_err(|_| {
+            anyhow!(
+                "Invalid log level '{}'. Valid options are: off, error, warn, info, debug, trace",
+                self.level
+            )
+        })?;
+        // The builder's default directive is only used without any other
+        // directives, so the level goes in front of the per-target ones
+        let directives = if self.filter.trim().is_empty() {
+            level.to_string()
+        } else {
+            format!("{},{}", level, self.filter)
+        };
+        EnvFilter::builder()
+            .parse(&directives)
+            .map_err(|e| anyhow!("Invalid 'logging.filter' '{}': {}", self.filter, e))
+    }
+}
+
+/// Install the global logger, writing to stdout.
+///
+/// A non-empty `RUST_LOG` replaces the configured level and filter, which is
+/// handy for a one-off debugging session. Colours are only used on a terminal,
+/// so call this after daemonizing. With `notifications`, the bot's warnings and
+/// errors are also queued for the admin room, whatever the log filter.
+pub fn init_logging(config: &LoggingConfig, notifications: Option<&Notifications>) -> Result<()> {
+    let filter = match std::env::var(LOG_FILTER_ENV) {
+        Ok(directives) if !directives.is_empty() => EnvFilter::builder()
+            .parse(&directives)
+            .map_err(|e| anyhow!("Invalid {} '{}': {}", LOG_FILTER_ENV, directives, e))?,
+        _ => config.env_filter()?,
+    };
+
+    let output = match config.format {
+        LogFormat::Human => tracing_subscriber::fmt::layer()
+            .with_ansi(std::io::stdout().is_terminal())
+            .boxed(),
+        LogFormat::Json => tracing_subscriber::fmt::layer()
+            .json()
+            .with_current_span(false)
+            .boxed(),
+    };
+    tracing_subscriber::registry()
+        .with(output.with_filter(filter))
+        .with(notifications.map(|notifications| notifications.layer()))
+        .try_init()
+        .map_err(|e| anyhow!("Failed to set up logging: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_env_filter_combines_level_and_filter() {
+        // Given a debug level with a quieter matrix_sdk
+        let config = LoggingConfig {
+            level: "debug".to_string(),
+            format: LogFormat::Json,
+            filter: "matrix_sdk=warn,matrix_sdk_crypto=error".to_string(),
+        };
+
+        // When building the filter
+        let filter = config.env_filter().unwrap();
+
+        // Then both the level and the per-target directives should be used
+        assert_eq!(filter.max_level_hint(), Some(LevelFilter::DEBUG));
+        let directives = filter.to_string();
+        assert!(directives.contains("matrix_sdk=warn"), "{}", directives);
+        assert!(
+            directives.contains("matrix_sdk_crypto=error"),
+            "{}",
+            directives
+        );
+    }
+
+    #[test]
+    fn test_env_filter_rejects_invalid_values() {
+        // Given an unknown level and a malformed filter
+        let bad_level = LoggingConfig {
+            level: "loud".to_string(),
+            ..LoggingConfig::default()
+        };
+        let bad_filter = LoggingConfig {
+            filter: "matrix_sdk=[".to_string(),
+            ..LoggingConfig::default()
+        };
+
+        // When building the filters
+        // Then both should be rejected with the offending value
+        assert!(
+            bad_level
+                .env_filter()
+                .unwrap_err()
+                .to_string()
+                .contains("Invalid log level 'loud'")
+        );
+        assert!(
+            bad_filter
+                .env_filter()
+                .unwrap_err()
+                .to_string()
+                .contains("Invalid 'logging.filter' 'matrix_sdk=['")
+        );
+    }
+}
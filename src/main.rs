@@ -1,49 +1,212 @@
 use anyhow::{Context, Result};
-use clap::Parser;
+use clap::{Args, CommandFactory, Parser, Subcommand, ValueEnum, error::ErrorKind};
 use daemonize::Daemonize;
-use matrix_bot_help::{Config, HelpFormat, load_help_text, load_welcome_text, should_ignore_user};
+use matrix_bot_help::pidfile::{is_process_alive, pid_status, terminate_process};
+use matrix_bot_help::{
+    ADMIN_HELP, AdminCommand, AuthenticationError, BotFilteringConfig, Config, CustomCommand,
+    Delivery, EXIT_AUTH_FAILURE, FaqCooldowns, FaqEntry, FaqMatcher, GiveUpAction, Health,
+    HelpFormat, HousekeepingConfig, IgnoreList, InviteOutcome, JoinDetectionConfig, LogFile,
+    LogFormat, Metrics, Notifications, PidFile, PidStatus, Readiness, RetryPolicy, RoomActivity,
+    RoomSnapshot, Shutdown, StatsReport, UsageStats, Watchdog, WelcomeOutcome,
+    accept_verification_request, bind_http, check_config, configured_token_replaces, find_command,
+    format_uptime, help_with_commands, http_listeners, init_logging, is_help_command,
+    is_rejected_credentials, is_retryable_sync_error, is_unknown_token, is_verification_allowed,
+    keep_reason, leave_reason, load_help_text, load_session, load_welcome_text, notify_ready,
+    notify_status, notify_stopping, parse_admin_command, render_message, retry_with_policy,
+    run_with_reconnect, save_session, serve_http, should_ignore_user, watchdog_timeout,
+    welcome_message_text,
+};
 use matrix_sdk::{
-    Client, Room, RoomState, SessionMeta, SessionTokens,
+    Client, Room, RoomState, SessionChange, SessionMeta, SessionTokens,
     authentication::matrix::MatrixSession,
     config::SyncSettings,
+    encryption::{BackupDownloadStrategy, EncryptionSettings, recovery::RecoveryState},
+    ruma::api::client::uiaa,
+    ruma::events::AnySyncTimelineEvent,
+    ruma::events::key::verification::request::ToDeviceKeyVerificationRequestEvent,
+    ruma::events::room::encrypted::OriginalSyncRoomEncryptedEvent,
     ruma::events::room::member::{MembershipState, StrippedRoomMemberEvent, SyncRoomMemberEvent},
     ruma::events::room::message::{
-        MessageType, OriginalSyncRoomMessageEvent, RoomMessageEventContent,
+        AddMentions, ForwardThread, MessageType, OriginalSyncRoomMessageEvent,
+        RoomMessageEventContent,
     },
-    ruma::{UserId, device_id},
+    ruma::events::room::power_levels::UserPowerLevel,
+    ruma::{Int, MilliSecondsSinceUnixEpoch, OwnedRoomId, OwnedUserId, RoomId, UserId},
 };
+use std::cell::Cell;
+use std::collections::HashSet;
 use std::fs::{self, OpenOptions};
-use std::sync::Arc;
-use std::time::{Duration, Instant};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::signal::unix::{Signal, SignalKind, signal};
 use tokio::sync::RwLock;
+use tracing::{Instrument, debug, error, info, instrument, warn};
 
 #[derive(Parser)]
 #[command(name = "matrix-bot-help")]
 #[command(about = "A Matrix bot for help")]
 struct Cli {
     /// Config file path
-    #[arg(short, long, default_value = "bot.toml")]
+    #[arg(short, long, default_value = "bot.toml", global = true)]
     config: String,
 
+    /// Options for running the bot when no subcommand is given
+    #[command(flatten)]
+    run: RunArgs,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Args)]
+struct RunArgs {
     /// Daemonize the process
     #[arg(short = 'd', long, default_value = "false")]
     daemonize: bool,
 }
 
+#[derive(Subcommand)]
+enum Command {
+    /// Run the bot (the default when no subcommand is given)
+    Run(RunArgs),
+    /// Set up cross-signing and key backup for the bot account and print the recovery key
+    BootstrapEncryption {
+        /// Replace an existing recovery key with a new one
+        #[arg(long)]
+        reset_recovery_key: bool,
+    },
+    /// Print the exact message the bot would send, to preview help and welcome files
+    Render {
+        /// Which message to render
+        #[arg(value_enum, default_value = "help")]
+        message: RenderedMessage,
+        /// Render this file instead of the one from the config
+        #[arg(long)]
+        file: Option<String>,
+        /// Format to render the file in (plain, html, markdown), instead of the one from the config
+        #[arg(long)]
+        format: Option<HelpFormat>,
+        /// User the welcome message is addressed to
+        #[arg(long, default_value = "@user:example.com")]
+        user: String,
+    },
+    /// Stop the running instance recorded in the PID file
+    Stop {
+        /// Seconds to wait for the bot to exit
+        #[arg(long, default_value = "30")]
+        timeout: u64,
+    },
+    /// Show whether the instance recorded in the PID file is running
+    Status,
+    /// Validate the config and message files, then exit without starting the bot
+    CheckConfig {
+        /// Also verify the credentials against the homeserver
+        #[arg(long)]
+        whoami: bool,
+    },
+    /// Print the usage statistics recorded by the bot
+    Stats {
+        /// Output format
+        #[arg(long, value_enum, default_value = "text")]
+        format: StatsFormat,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum StatsFormat {
+    Text,
+    Csv,
+    Json,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum RenderedMessage {
+    Help,
+    Welcome,
+}
+
 fn main() -> Result<()> {
     let cli = Cli::parse();
-    println!("Using config file: {}", cli.config);
-    println!("Daemonize: {}", cli.daemonize);
 
-    // Read and parse config file
-    let config_content = fs::read_to_string(&cli.config)
-        .with_context(|| format!("Failed to read config file '{}'", cli.config))?;
+    // Running is the default, so its options are also accepted without a subcommand
+    let command = match cli.command {
+        None => Command::Run(cli.run),
+        Some(Command::Run(args)) => Command::Run(RunArgs {
+            daemonize: args.daemonize || cli.run.daemonize,
+        }),
+        Some(_) if cli.run.daemonize => Cli::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "'--daemonize' can only be given when running the bot",
+            )
+            .exit(),
+        Some(command) => command,
+    };
 
-    // Parse configuration from TOML
-    let config = Config::from_toml(&config_content).context("Failed to parse config")?;
+    match command {
+        Command::Run(args) => run_command(&cli.config, args),
+        Command::BootstrapEncryption { reset_recovery_key } => {
+            let config = load_config_verbose(&cli.config)?;
+            init_logging(&config.logging, None)?;
+            bootstrap_encryption(&config, reset_recovery_key)
+        }
+        Command::CheckConfig { whoami } => {
+            let config = load_config_verbose(&cli.config)?;
+            // Shows what the login for --whoami does, e.g. which session it uses
+            init_logging(&config.logging, None)?;
+            check_config_command(&config, whoami)
+        }
+        Command::Stop { timeout } => stop_command(&load_config(&cli.config)?, timeout),
+        Command::Status => status_command(&load_config(&cli.config)?),
+        Command::Stats { format } => stats_command(&load_config(&cli.config)?, format),
+        Command::Render {
+            message,
+            file,
+            format,
+            user,
+        } => {
+            // Rendering only needs the config for what isn't given on the command
+            // line, and its output shouldn't be mixed with the config dump
+            let config = if file.is_some() && format.is_some() {
+                None
+            } else {
+                Some(load_config(&cli.config)?)
+            };
+            render_command(
+                config.as_ref(),
+                message,
+                file.as_deref(),
+                format.as_ref(),
+                &user,
+            )
+        }
+    }
+}
+
+/// Read and parse the config file, applying environment overrides.
+fn load_config(path: &str) -> Result<Config> {
+    let config_content = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read config file '{}'", path))?;
+    Config::from_toml_with_env(&config_content, std::env::vars()).context("Failed to parse config")
+}
 
+/// Load the config and print it, for subcommands whose output is a log.
+fn load_config_verbose(path: &str) -> Result<Config> {
+    println!("Using config file: {}", path);
+    let config = load_config(path)?;
     println!("Config loaded:");
     config.print();
+    Ok(config)
+}
+
+/// Run the bot until it stops, optionally as a daemon.
+fn run_command(config_path: &str, args: RunArgs) -> Result<()> {
+    let mut config = load_config(config_path)?;
+    // The daemon changes into the working directory, so resolve it first and
+    // derive the session, store, stats, ignore and PID files from that
+    config.resolve_working_dir()?;
+    let config = &config;
 
     // Verify help file exists before daemonizing
     if !std::path::Path::new(&config.help_file).exists() {
@@ -53,6 +216,19 @@ fn main() -> Result<()> {
         ));
     }
 
+    // Verify command response files exist
+    for command in &config.commands {
+        if let Some(ref file) = command.file
+            && !std::path::Path::new(file).exists()
+        {
+            return Err(anyhow::anyhow!(
+                "Response file '{}' of command '{}' does not exist",
+                file,
+                command.name
+            ));
+        }
+    }
+
     // Verify welcome file exists if specified
     if let Some(ref welcome_file) = config.join_detection.welcome_file
         && !std::path::Path::new(welcome_file).exists()
@@ -63,8 +239,27 @@ fn main() -> Result<()> {
         ));
     }
 
+    let pid_path = config.pid_path();
+
+    // Refuse to start next to a running instance while errors still reach the terminal
+    if let PidStatus::Running(pid) = pid_status(&pid_path)? {
+        return Err(anyhow::anyhow!(
+            "Another instance is already running with PID {} (PID file '{}')",
+            pid,
+            pid_path.display()
+        ));
+    }
+
+    // Resolved before daemonizing, so `!bot reload` can find it
+    let config_path = std::path::absolute(config_path)
+        .with_context(|| format!("Invalid config file path '{}'", config_path))?;
+
     // Daemonize if requested
-    if cli.daemonize {
+    let mut log_file = None;
+    if args.daemonize {
+        // Resolved before the daemon changes its working directory, to reopen it later
+        let log_path = std::path::absolute(&config.log_file)
+            .with_context(|| format!("Invalid log file path '{}'", config.log_file))?;
         let log_file_handle = OpenOptions::new()
             .create(true)
             .append(true)
@@ -72,7 +267,6 @@ fn main() -> Result<()> {
             .with_context(|| format!("Failed to open log file '{}'", config.log_file))?;
 
         let daemonize = Daemonize::new()
-            .pid_file("/tmp/matrix-bot-help.pid")
             .working_directory(&config.working_dir)
             .stdout(
                 log_file_handle
@@ -82,62 +276,186 @@ fn main() -> Result<()> {
             .stderr(log_file_handle);
 
         daemonize.start().context("Failed to daemonize")?;
-
-        println!("Successfully daemonized, PID: {}", std::process::id());
-        config.print();
+        log_file = Some(Arc::new(Mutex::new(LogFile::new(
+            log_path,
+            config.log_rotation.clone(),
+        )?)));
 
         // Bot logic runs here after daemonizing
     }
 
-    run_bot(&config)?;
+    // Kept across logins, so nothing queued for the admin room is lost
+    let notifications = config
+        .notifications
+        .enabled
+        .then(|| Notifications::new(&config.notifications));
+
+    // Set up after daemonizing, so that colours are only used on a terminal
+    init_logging(&config.logging, notifications.as_ref())?;
+    info!(
+        config_file = %config_path.display(),
+        daemonize = args.daemonize,
+        pid = std::process::id(),
+        "Starting"
+    );
+    if config.logging.format == LogFormat::Human {
+        config.print();
+    }
+
+    // Written after daemonizing, so it holds the PID of the daemon
+    let pid_file = PidFile::acquire(&pid_path)?;
+
+    // Kept across logins, so counters don't reset when logging in again
+    let metrics = Metrics::new();
+    let health = Health::new(&config.health);
 
-    println!("Bye.");
+    // Run the bot, logging in again with the password if the homeserver rejects
+    // a session that used to work. Another relogin is only allowed once a sync
+    // succeeded with the new session, so rejected fresh tokens don't loop forever
+    let mut relogin = false;
+    loop {
+        let Err(e) = run_bot(
+            &config_path,
+            config,
+            relogin,
+            log_file.as_ref(),
+            &metrics,
+            &health,
+            notifications.as_ref(),
+        ) else {
+            break;
+        };
+        match e.downcast_ref::<AuthenticationError>() {
+            Some(auth_error)
+                if auth_error.after_login
+                    && config.password.is_some()
+                    && (!relogin || health.has_synced()) =>
+            {
+                warn!(error = %auth_error, "Logging in again with password");
+                relogin = true;
+            }
+            Some(auth_error) => {
+                error!(error = %auth_error, "Authentication failed, exiting");
+                drop(pid_file);
+                std::process::exit(EXIT_AUTH_FAILURE);
+            }
+            None => {
+                // Logged rather than returned, so that it ends up in the log
+                // in the configured format
+                error!(error = format!("{:#}", e), "Stopped after an error");
+                drop(pid_file);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    info!("Bye.");
     Ok(())
 }
 
 #[tokio::main]
-async fn run_bot(config: &Config) -> Result<()> {
-    println!("Starting Matrix bot with homeserver: {}", config.homeserver);
+async fn run_bot(
+    config_path: &Path,
+    config: &Config,
+    relogin: bool,
+    log_file: Option<&Arc<Mutex<LogFile>>>,
+    metrics: &Metrics,
+    health: &Health,
+    notifications: Option<&Notifications>,
+) -> Result<()> {
+    info!(homeserver = %config.homeserver, "Starting Matrix bot");
+    health.reset();
+    notify_status("logging in");
 
-    // Create client
-    let client = Client::builder()
-        .homeserver_url(&config.homeserver)
-        .build()
-        .await?;
+    // Serve metrics and health checks from the start, so failing logins and
+    // syncs can be seen
+    for (address, endpoints) in http_listeners(config, metrics, health) {
+        let listener = bind_http(&address).await?;
+        info!(%address, "Serving {}", endpoints.describe());
+        tokio::spawn(serve_http(listener, endpoints));
+    }
 
-    // Create a MatrixSession with existing access token
-    let user_id = UserId::parse(&config.username)
-        .map_err(|e| anyhow::anyhow!("Invalid user ID '{}': {}", config.username, e))?;
+    // Rotate the log file when it is due, or when asked to with SIGUSR1
+    if let Some(log_file) = log_file {
+        let mut rotate_signal =
+            signal(SignalKind::user_defined1()).context("Failed to handle SIGUSR1")?;
+        let log_file = log_file.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(10)); // Check size and age every 10 seconds
+            loop {
+                let result = tokio::select! {
+                    _ = rotate_signal.recv() => {
+                        info!("Received SIGUSR1, rotating log file");
+                        log_file.lock().expect("Log file lock poisoned").rotate_or_reopen()
+                    }
+                    _ = interval.tick() => {
+                        log_file.lock().expect("Log file lock poisoned").rotate_if_due()
+                    }
+                };
+                if let Err(e) = result {
+                    error!(error = format!("{:#}", e), "Failed to rotate log file");
+                }
+            }
+        });
+    }
 
-    let session = MatrixSession {
-        meta: SessionMeta {
-            user_id,
-            device_id: device_id!("matrix-bot-help").to_owned(),
-        },
-        tokens: SessionTokens {
-            access_token: config.access_token.clone(),
-            refresh_token: None,
-        },
-    };
+    let client = build_client(config).await?;
 
-    // Restore the session with access token
-    client
-        .matrix_auth()
-        .restore_session(session, matrix_sdk::store::RoomLoadSettings::default())
-        .await?;
+    log_in(&client, config, relogin).await?;
 
-    println!("Successfully logged in as {}", config.username);
+    if config.encryption.enabled {
+        set_up_encryption(&client, config).await?;
+    }
 
-    // Initial sync to avoid responding to old messages
-    let response = client.sync_once(SyncSettings::default()).await?;
-    println!("Initial sync completed");
+    info!(user_id = %config.username, "Successfully logged in");
+    health.logged_in();
 
-    // Load help text at startup
-    let help_text = load_help_text(&config.help_file).context("Failed to load help text")?;
+    // Handle SIGTERM and SIGINT from here on, so a shutdown during the initial
+    // sync is still graceful
+    let mut signals = ShutdownSignals::new()?;
+    let shutdown = Shutdown::new();
 
-    // Load welcome text at startup if welcome_file is specified
-    let welcome_text = if let Some(ref welcome_file) = config.join_detection.welcome_file {
-        Some(load_welcome_text(welcome_file).context("Failed to load welcome text")?)
+    // Persist tokens whenever the SDK rotates them
+    let mut session_changes = client.subscribe_to_session_changes();
+    let session_client = client.clone();
+    let session_path = config.session_path();
+    tokio::spawn(async move {
+        while let Ok(change) = session_changes.recv().await {
+            match change {
+                SessionChange::TokensRefreshed => {
+                    let Some(session) = session_client.matrix_auth().session() else {
+                        continue;
+                    };
+                    match save_session(&session_path, &session) {
+                        Ok(()) => info!(
+                            path = %session_path.display(),
+                            "Access token refreshed, saved session"
+                        ),
+                        Err(e) => {
+                            error!(
+                                error = format!("{:#}", e),
+                                "Failed to save refreshed session"
+                            )
+                        }
+                    }
+                }
+                SessionChange::UnknownToken { soft_logout } => {
+                    warn!(soft_logout, "Homeserver rejected the access token");
+                }
+            }
+        }
+    });
+
+    // Events sent before this point are never answered, whether they arrive in
+    // the initial sync or while catching up from a stored sync token
+    let started_at = MilliSecondsSinceUnixEpoch::now();
+
+    // Load help and welcome texts at startup, and again on `!bot reload`
+    let settings = Settings::load(config)?;
+    let ignore_list = IgnoreList::load(&config.ignore_path())?;
+    let stats = if config.stats.enabled {
+        let stats = UsageStats::load(&config.stats_path(), &config.stats.privacy)?;
+        Some(Arc::new(Mutex::new(stats)))
     } else {
         None
     };
@@ -148,65 +466,891 @@ async fn run_bot(config: &Config) -> Result<()> {
         .expect("Client should have a user ID")
         .to_owned();
 
-    // Add event handler for room messages
-    let bot_filtering = config.bot_filtering.clone();
-    let help_format = config.help_format.clone();
+    let context = EventContext {
+        started_at,
+        started: Instant::now(),
+        config_path: config_path.to_path_buf(),
+        settings: Arc::new(std::sync::RwLock::new(Arc::new(settings))),
+        ignore_list: Arc::new(Mutex::new(ignore_list)),
+        stats,
+        faq_cooldowns: Arc::default(),
+        metrics: metrics.clone(),
+        health: health.clone(),
+        notifications: notifications.cloned(),
+    };
+
+    // Post warnings, errors, invites and joins to the admin room
+    if let Some(notifications) = notifications
+        && let Some(ref admin_room) = config.admin_room
+    {
+        match OwnedRoomId::try_from(admin_room.as_str()) {
+            Ok(room_id) => {
+                info!(%room_id, "Posting notifications to the admin room");
+                tokio::spawn(
+                    notifications
+                        .clone()
+                        .run(client.clone(), room_id, metrics.clone()),
+                );
+            }
+            Err(e) => warn!(error = %e, "Invalid admin room, not posting notifications"),
+        }
+    }
+
+    // Add event handler for room messages and admin commands
+    let message_shutdown = shutdown.clone();
+    let message_context = context.clone();
     client.add_event_handler(
         move |event: OriginalSyncRoomMessageEvent, room: Room| async move {
-            on_room_message(
+            let Some(_operation) = message_shutdown.start_operation() else {
+                return;
+            };
+            on_room_message(event, room, &message_context, &bot_user_id).await
+        },
+    );
+
+    // Log messages that could not be decrypted, since they can't be answered
+    if config.encryption.enabled {
+        client.add_event_handler(
+            |event: OriginalSyncRoomEncryptedEvent, room: Room| async move {
+                warn!(
+                    room_id = %room.room_id(),
+                    sender = %event.sender,
+                    event_id = %event.event_id,
+                    "Unable to decrypt message"
+                );
+            },
+        );
+    }
+
+    // Accept verification requests from admins so they can verify the bot's device
+    if config.encryption.enabled {
+        let verification_context = context.clone();
+        client.add_event_handler(
+            move |event: ToDeviceKeyVerificationRequestEvent, client: Client| {
+                let admins = verification_context.settings().admins.clone();
+                async move {
+                    on_verification_request(
+                        client,
+                        event.sender,
+                        event.content.transaction_id.to_string(),
+                        &admins,
+                    )
+                    .await
+                }
+            },
+        );
+
+        let verification_context = context.clone();
+        client.add_event_handler(move |event: OriginalSyncRoomMessageEvent, client: Client| {
+            let admins = verification_context.settings().admins.clone();
+            async move {
+                if let MessageType::VerificationRequest(_) = event.content.msgtype {
+                    on_verification_request(
+                        client,
+                        event.sender,
+                        event.event_id.to_string(),
+                        &admins,
+                    )
+                    .await
+                }
+            }
+        });
+    }
+
+    // Add event handler for autojoining rooms when invited
+    let join_retry = config.join_retry.clone();
+    let invite_shutdown = shutdown.clone();
+    let invite_context = context.clone();
+    client.add_event_handler(
+        move |event: StrippedRoomMemberEvent, client: Client, room: Room| async move {
+            on_stripped_state_member(
                 event,
+                client,
                 room,
-                &help_text,
-                &bot_user_id,
-                &bot_filtering,
-                &help_format,
+                &join_retry,
+                &invite_shutdown,
+                &invite_context,
             )
             .await
         },
     );
 
-    // Add event handler for autojoining rooms when invited
-    client.add_event_handler(on_stripped_state_member);
-
     // Add event handler for detecting when users join rooms
-    let join_detection_config = config.join_detection.clone();
-    let bot_filtering = config.bot_filtering.clone();
     let welcomed_users = Arc::new(RwLock::new(
         std::collections::HashSet::<(String, Instant)>::new(),
     ));
     let welcomed_users_clone = welcomed_users.clone();
+    let member_shutdown = shutdown.clone();
+    let member_context = context.clone();
 
     client.add_event_handler(move |event: SyncRoomMemberEvent, room: Room| async move {
-        on_room_member(
-            event,
-            room,
-            &join_detection_config,
-            &bot_filtering,
-            welcomed_users_clone.clone(),
-            &welcome_text,
-        )
-        .await
+        let Some(_operation) = member_shutdown.start_operation() else {
+            return;
+        };
+        on_room_member(event, room, &member_context, welcomed_users_clone.clone()).await
     });
 
     // Start cleanup task for welcomed users
     let cleanup_users = welcomed_users.clone();
-    let cleanup_timeout = config.join_detection.welcome_timeout_seconds;
+    let cleanup_context = context.clone();
     tokio::spawn(async move {
         let mut interval = tokio::time::interval(Duration::from_secs(300)); // Clean up every 5 minutes
         loop {
             interval.tick().await;
+            let cleanup_timeout = cleanup_context
+                .settings()
+                .join_detection
+                .welcome_timeout_seconds;
             cleanup_welcomed_users(cleanup_users.clone(), cleanup_timeout).await;
         }
     });
 
-    // Start continuous sync
-    let settings = SyncSettings::default().token(response.next_batch);
-    println!("Starting continuous sync...");
-    client.sync(settings).await?;
+    // Track room activity and periodically leave unused rooms if enabled
+    let room_activity = if config.housekeeping.enabled {
+        let activity = RoomActivity::load(&config.activity_path(), SystemTime::now())?;
+        Some(Arc::new(RwLock::new(activity)))
+    } else {
+        None
+    };
+    if let Some(ref room_activity) = room_activity {
+        let activity = room_activity.clone();
+        client.add_event_handler(move |event: AnySyncTimelineEvent, room: Room| {
+            let activity = activity.clone();
+            async move {
+                let client = room.client();
+                if Some(event.sender()) != client.user_id()
+                    && event.origin_server_ts() >= started_at
+                {
+                    activity
+                        .write()
+                        .await
+                        .record(room.room_id().as_str(), SystemTime::now());
+                }
+            }
+        });
+
+        let housekeeping_client = client.clone();
+        let housekeeping_config = config.housekeeping.clone();
+        let housekeeping_activity = room_activity.clone();
+        let housekeeping_shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            let mut interval =
+                tokio::time::interval(Duration::from_secs(housekeeping_config.interval_seconds));
+            loop {
+                interval.tick().await;
+                let Some(_operation) = housekeeping_shutdown.start_operation() else {
+                    break;
+                };
+                run_housekeeping(
+                    &housekeeping_client,
+                    &housekeeping_config,
+                    &housekeeping_activity,
+                )
+                .await;
+            }
+        });
+        info!("Housekeeping task started");
+    }
+
+    // Under systemd with WatchdogSec, ping the watchdog while syncs keep completing
+    let watchdog = watchdog_timeout().map(|timeout| {
+        info!(
+            timeout_seconds = timeout.as_secs_f64(),
+            "Pinging the systemd watchdog"
+        );
+        let watchdog = Watchdog::new(timeout);
+        tokio::spawn(watchdog.clone().run());
+        watchdog
+    });
+
+    // Sync until shut down, reconnecting after transient errors. Every sync
+    // continues from the last sync token, which is the stored one at first
+    notify_status("initial sync");
+    let initial_sync_done = Cell::new(false);
+    let sync = run_with_reconnect(
+        &config.sync_retry,
+        || async {
+            client.sync_once(SyncSettings::default()).await?;
+            health.synced();
+            if let Some(ref watchdog) = watchdog {
+                watchdog.progress();
+            }
+            if !initial_sync_done.replace(true) {
+                info!("Initial sync completed, starting continuous sync");
+                notify_ready("syncing");
+            }
+            Ok(())
+        },
+        is_retryable_sync_error,
+        |failures, e, delay| {
+            metrics.sync_error();
+            if let Some(ref watchdog) = watchdog {
+                watchdog.waiting(delay);
+            }
+            notify_status(&format!("reconnecting (attempt {})", failures));
+            warn!(
+                error = %e,
+                attempt = failures,
+                delay_seconds = delay.as_secs_f64(),
+                "Sync failed, reconnecting"
+            );
+        },
+        |failures| {
+            metrics.reconnect();
+            notify_status("syncing");
+            info!(failed_attempts = failures, "Reconnected to the homeserver");
+        },
+    );
+    tokio::pin!(sync);
+    let signal = tokio::select! {
+        error = &mut sync => return Err(auth_error(error)),
+        signal = signals.recv() => signal,
+    };
+
+    info!("Received {}, shutting down", signal);
+    shutdown.trigger();
+    health.shutting_down();
+    notify_stopping();
+
+    // Keep syncing while waiting, since the SDK may run handlers as part of the
+    // sync, but they no longer start new work
+    let timeout = Duration::from_secs(config.shutdown_timeout_seconds);
+    tokio::select! {
+        _ = &mut sync => {}
+        idle = tokio::time::timeout(timeout, shutdown.idle()) => {
+            if idle.is_err() {
+                warn!(
+                    in_flight = shutdown.in_flight(),
+                    "Gave up waiting for running operations after {} seconds",
+                    timeout.as_secs()
+                );
+            }
+        }
+        signal = signals.recv() => warn!("Received {} again, shutting down immediately", signal),
+    }
+
+    // Save the latest tokens, in case a refresh raced with the shutdown
+    let session_path = config.session_path();
+    if session_path.exists()
+        && let Some(session) = client.matrix_auth().session()
+    {
+        save_session(&session_path, &session)?;
+    }
+    if let Some(room_activity) = room_activity {
+        room_activity.read().await.save()?;
+    }
+
+    Ok(())
+}
+
+/// Settings that `!bot reload` replaces without logging in again.
+struct Settings {
+    /// Help text with the custom commands listed
+    help_text: String,
+    help_format: HelpFormat,
+    /// Contents of `join_detection.welcome_file`, if set
+    welcome_text: Option<String>,
+    commands: Vec<CustomCommand>,
+    /// Set if FAQ answers are enabled
+    faq: Option<FaqMatcher>,
+    join_detection: JoinDetectionConfig,
+    bot_filtering: BotFilteringConfig,
+    admins: Vec<String>,
+    admin_room: Option<String>,
+    admin_power_level: i64,
+}
+
+impl Settings {
+    fn load(config: &Config) -> Result<Self> {
+        let help_text = load_help_text(&config.help_file).context("Failed to load help text")?;
+        let welcome_text = match config.join_detection.welcome_file {
+            Some(ref welcome_file) => {
+                Some(load_welcome_text(welcome_file).context("Failed to load welcome text")?)
+            }
+            None => None,
+        };
+        let commands = config
+            .commands
+            .iter()
+            .map(CustomCommand::load)
+            .collect::<Result<Vec<_>>>()
+            .context("Failed to load command responses")?;
+        let faq = if config.faq.enabled {
+            Some(FaqMatcher::new(&config.faq)?)
+        } else {
+            None
+        };
+        Ok(Self {
+            help_text: help_with_commands(&help_text, &config.help_format, &config.commands),
+            help_format: config.help_format.clone(),
+            welcome_text,
+            commands,
+            faq,
+            join_detection: config.join_detection.clone(),
+            bot_filtering: config.bot_filtering.clone(),
+            admins: config.admins.clone(),
+            admin_room: config.admin_room.clone(),
+            admin_power_level: config.admin_power_level,
+        })
+    }
+}
+
+impl Settings {
+    /// The response for a topic, which is `help` or the name of a custom command.
+    fn topic_response(&self, topic: &str) -> Option<RoomMessageEventContent> {
+        if topic == "help" {
+            return Some(render_message(&self.help_text, &self.help_format));
+        }
+        self.commands
+            .iter()
+            .find(|command| command.config.name == topic)
+            .map(|command| render_message(&command.response, &command.config.format))
+    }
+}
+
+/// State shared by the handlers of room events.
+#[derive(Clone)]
+struct EventContext {
+    /// Events sent before this point are never answered
+    started_at: MilliSecondsSinceUnixEpoch,
+    /// When the bot logged in, for `!bot status`
+    started: Instant,
+    /// Absolute path of the config file, for `!bot reload`
+    config_path: PathBuf,
+    settings: Arc<std::sync::RwLock<Arc<Settings>>>,
+    ignore_list: Arc<Mutex<IgnoreList>>,
+    /// Usage statistics, if enabled
+    stats: Option<Arc<Mutex<UsageStats>>>,
+    /// When each FAQ was last answered in each room, kept across reloads
+    faq_cooldowns: Arc<Mutex<FaqCooldowns>>,
+    metrics: Metrics,
+    health: Health,
+    /// Queue for the admin room, if notifications are enabled
+    notifications: Option<Notifications>,
+}
+
+impl EventContext {
+    /// The current settings, which stay the same for the caller even if reloaded meanwhile.
+    fn settings(&self) -> Arc<Settings> {
+        self.settings
+            .read()
+            .expect("Settings lock poisoned")
+            .clone()
+    }
+
+    fn ignore_list(&self) -> std::sync::MutexGuard<'_, IgnoreList> {
+        self.ignore_list.lock().expect("Ignore list lock poisoned")
+    }
+
+    /// Update the usage statistics, if enabled.
+    fn record_stats(&self, record: impl FnOnce(&mut UsageStats) -> Result<()>) {
+        if let Some(ref stats) = self.stats
+            && let Err(e) = record(&mut stats.lock().expect("Stats lock poisoned"))
+        {
+            warn!(
+                error = format!("{:#}", e),
+                "Failed to save usage statistics"
+            );
+        }
+    }
+
+    /// Whether a user is ignored by bot filtering or with `!bot ignore`.
+    fn is_ignored(&self, settings: &Settings, user_id: &str, bot_user_id: &str) -> bool {
+        should_ignore_user(user_id, bot_user_id, &settings.bot_filtering)
+            || self.ignore_list().contains(user_id)
+    }
+}
+
+/// SIGTERM and SIGINT, which both ask the bot to shut down.
+struct ShutdownSignals {
+    terminate: Signal,
+    interrupt: Signal,
+}
+
+impl ShutdownSignals {
+    fn new() -> Result<Self> {
+        Ok(Self {
+            terminate: signal(SignalKind::terminate()).context("Failed to handle SIGTERM")?,
+            interrupt: signal(SignalKind::interrupt()).context("Failed to handle SIGINT")?,
+        })
+    }
+
+    /// Wait for the next signal, returning its name.
+    async fn recv(&mut self) -> &'static str {
+        tokio::select! {
+            _ = self.terminate.recv() => "SIGTERM",
+            _ = self.interrupt.recv() => "SIGINT",
+        }
+    }
+}
+
+/// Finish setting up end-to-end encryption after logging in.
+///
+/// If a recovery key is configured and recovery is not yet enabled for this
+/// device, it is used to fetch the cross-signing secrets and the key backup.
+async fn set_up_encryption(client: &Client, config: &Config) -> Result<()> {
+    let encryption = client.encryption();
+    encryption.wait_for_e2ee_initialization_tasks().await;
+
+    if let Some(ref recovery_key) = config.encryption.recovery_key {
+        let recovery = encryption.recovery();
+        if recovery.state() == RecoveryState::Enabled {
+            info!("Recovery is already enabled for this device");
+        } else {
+            recovery
+                .recover(recovery_key)
+                .await
+                .context("Failed to recover secrets with the configured recovery key")?;
+            info!("Recovered cross-signing secrets and key backup");
+        }
+    }
+
+    info!(
+        recovery_state = ?encryption.recovery().state(),
+        "End-to-end encryption enabled"
+    );
+    Ok(())
+}
+
+/// Turn an SDK error into an [`AuthenticationError`] if the access token was rejected.
+fn auth_error(error: matrix_sdk::Error) -> anyhow::Error {
+    if is_unknown_token(&error) {
+        AuthenticationError {
+            after_login: true,
+            message: error.to_string(),
+        }
+        .into()
+    } else {
+        error.into()
+    }
+}
+
+/// Create a client for the configured homeserver, with an on-disk store if one is configured.
+async fn build_client(config: &Config) -> Result<Client> {
+    let mut builder = Client::builder()
+        .homeserver_url(&config.homeserver)
+        .handle_refresh_tokens();
+    if let Some(store_path) = config.store_path() {
+        // Keep state on disk, so restarts resume from the last sync token and,
+        // with encryption, the bot's identity and room keys survive
+        builder = builder.sqlite_store(store_path, config.store.passphrase.as_deref());
+    }
+    if config.encryption.enabled {
+        builder = builder.with_encryption_settings(EncryptionSettings {
+            auto_enable_cross_signing: false,
+            backup_download_strategy: BackupDownloadStrategy::AfterDecryptionFailure,
+            auto_enable_backups: false,
+        });
+    }
+    Ok(builder.build().await?)
+}
+
+/// Set up cross-signing and key backup for the bot account.
+///
+/// Prints the recovery key, which should then be added to the config so that
+/// the bot can restore its secrets if its store is ever lost.
+#[tokio::main]
+async fn bootstrap_encryption(config: &Config, reset_recovery_key: bool) -> Result<()> {
+    if !config.encryption.enabled {
+        return Err(anyhow::anyhow!(
+            "Encryption is not enabled, set 'enabled = true' in the [encryption] section"
+        ));
+    }
+
+    let client = build_client(config).await?;
+    log_in(&client, config, false).await?;
+
+    // Sync once so the device keys get uploaded before signing them
+    client
+        .sync_once(SyncSettings::default())
+        .await
+        .map_err(auth_error)?;
+    let encryption = client.encryption();
+    encryption.wait_for_e2ee_initialization_tasks().await;
+
+    if let Err(e) = encryption.bootstrap_cross_signing_if_needed(None).await {
+        let Some(response) = e.as_uiaa_response() else {
+            return Err(e).context("Failed to bootstrap cross-signing");
+        };
+        let password = config.password.as_ref().context(
+            "The homeserver requires a password to set up cross-signing, set 'password' in the config",
+        )?;
+        let mut auth = uiaa::Password::new(
+            uiaa::UserIdentifier::UserIdOrLocalpart(config.username.clone()),
+            password.clone(),
+        );
+        auth.session = response.session.clone();
+        encryption
+            .bootstrap_cross_signing(Some(uiaa::AuthData::Password(auth)))
+            .await
+            .context("Failed to bootstrap cross-signing")?;
+    }
+    println!("Cross-signing is set up for {}", config.username);
+
+    let recovery = encryption.recovery();
+    let recovery_key = match recovery.state() {
+        RecoveryState::Enabled if !reset_recovery_key => None,
+        RecoveryState::Enabled | RecoveryState::Incomplete => Some(
+            recovery
+                .reset_key()
+                .await
+                .context("Failed to reset the recovery key")?,
+        ),
+        RecoveryState::Disabled | RecoveryState::Unknown => Some(
+            recovery
+                .enable()
+                .wait_for_backups_to_upload()
+                .await
+                .context("Failed to enable key backup and recovery")?,
+        ),
+    };
+
+    match recovery_key {
+        Some(key) => {
+            println!("Key backup and recovery are enabled. Recovery key:");
+            println!();
+            println!("    {}", key);
+            println!();
+            println!("Store it safely and add it to the [encryption] section as 'recovery_key'.");
+        }
+        None => println!(
+            "Recovery is already enabled, use --reset-recovery-key to create a new recovery key"
+        ),
+    }
+
+    Ok(())
+}
+
+/// Print the body and formatted body of a help or welcome message.
+fn render_command(
+    config: Option<&Config>,
+    message: RenderedMessage,
+    file: Option<&str>,
+    format: Option<&HelpFormat>,
+    user: &str,
+) -> Result<()> {
+    let default_join_detection = JoinDetectionConfig::default();
+    let join_detection = config.map_or(&default_join_detection, |c| &c.join_detection);
+
+    let (text, configured_format) = match message {
+        RenderedMessage::Help => {
+            let help_file = file.or(config.map(|c| c.help_file.as_str()));
+            let help_file = help_file.context("No help file to render")?;
+            let help_text = load_help_text(help_file)?;
+            let format = format.or(config.map(|c| &c.help_format));
+            let commands = config.map_or(&[][..], |c| &c.commands);
+            (
+                help_with_commands(&help_text, format.unwrap_or(&HelpFormat::Plain), commands),
+                config.map(|c| &c.help_format),
+            )
+        }
+        RenderedMessage::Welcome => {
+            let welcome_file = file.or(join_detection.welcome_file.as_deref());
+            let welcome_text = welcome_file.map(load_welcome_text).transpose()?;
+            (
+                welcome_message_text(join_detection, welcome_text.as_deref(), user),
+                Some(&join_detection.welcome_format),
+            )
+        }
+    };
+    let format = format.or(configured_format).unwrap_or(&HelpFormat::Plain);
+
+    let content = render_message(&text, format);
+    let MessageType::Text(text_content) = content.msgtype else {
+        unreachable!("Rendered messages are always text messages");
+    };
+    println!("body:");
+    println!("{}", text_content.body);
+    println!();
+    match text_content.formatted {
+        Some(formatted) => {
+            println!("formatted_body ({}):", formatted.format.as_str());
+            println!("{}", formatted.body);
+        }
+        None => println!("formatted_body: [none]"),
+    }
+    Ok(())
+}
+
+/// Stop the running instance and wait for it to exit.
+fn stop_command(config: &Config, timeout: u64) -> Result<()> {
+    let pid_path = config.pid_path();
+    let pid = match pid_status(&pid_path)? {
+        PidStatus::Running(pid) => pid,
+        PidStatus::Stale(pid) => {
+            println!("Not running, removing stale PID file of process {}", pid);
+            return fs::remove_file(&pid_path)
+                .with_context(|| format!("Failed to remove PID file '{}'", pid_path.display()));
+        }
+        PidStatus::NotRunning => {
+            println!("Not running");
+            return Ok(());
+        }
+    };
+
+    println!("Stopping process {}", pid);
+    terminate_process(pid)?;
+    let deadline = Instant::now() + Duration::from_secs(timeout);
+    while is_process_alive(pid) {
+        if Instant::now() >= deadline {
+            return Err(anyhow::anyhow!(
+                "Process {} did not exit within {} seconds",
+                pid,
+                timeout
+            ));
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+
+    // Clean up after a process that exited without removing its PID file
+    if pid_status(&pid_path)? == PidStatus::Stale(pid) {
+        fs::remove_file(&pid_path)
+            .with_context(|| format!("Failed to remove PID file '{}'", pid_path.display()))?;
+    }
+    println!("Stopped");
+    Ok(())
+}
+
+/// Print the usage statistics, which can be read while the bot is running.
+fn stats_command(config: &Config, format: StatsFormat) -> Result<()> {
+    let report = StatsReport::read(&config.stats_path())?;
+    match format {
+        StatsFormat::Text => println!("{}", report.to_text(usize::MAX, |_| None)),
+        StatsFormat::Csv => print!("{}", report.to_csv()),
+        StatsFormat::Json => println!("{}", report.to_json()?),
+    }
+    Ok(())
+}
+
+/// Print whether the bot is running, exiting with the LSB status codes.
+fn status_command(config: &Config) -> Result<()> {
+    match pid_status(&config.pid_path())? {
+        PidStatus::Running(pid) => {
+            println!("Running with PID {}", pid);
+            Ok(())
+        }
+        PidStatus::Stale(pid) => {
+            println!(
+                "Not running, but a stale PID file of process {} exists",
+                pid
+            );
+            std::process::exit(1);
+        }
+        PidStatus::NotRunning => {
+            println!("Not running");
+            std::process::exit(3);
+        }
+    }
+}
+
+/// Report every problem in the config, failing if there is any.
+fn check_config_command(config: &Config, whoami: bool) -> Result<()> {
+    let mut problems = check_config(config);
+    if whoami && let Err(e) = check_whoami(config) {
+        problems.push(format!("{:#}", e));
+    }
+
+    if problems.is_empty() {
+        println!("Config is valid");
+        return Ok(());
+    }
+    eprintln!("Config has {} problem(s):", problems.len());
+    for problem in &problems {
+        eprintln!("  - {}", problem);
+    }
+    Err(anyhow::anyhow!("Config check failed"))
+}
+
+/// Verify the configured credentials with a `whoami` request.
+///
+/// Uses the same client as the bot, so an expired access token is refreshed
+/// like the bot would, and the rotated tokens are saved for its next start.
+#[tokio::main]
+async fn check_whoami(config: &Config) -> Result<()> {
+    let session_path = config.session_path();
+    if !session_path.exists() && config.access_token.is_none() {
+        println!("Skipping whoami: no session file or access token, only a password is configured");
+        return Ok(());
+    }
+
+    let client = build_client(config)
+        .await
+        .with_context(|| format!("Failed to connect to homeserver '{}'", config.homeserver))?;
+    log_in(&client, config, false).await?;
+    let tokens = client.matrix_auth().session().map(|session| session.tokens);
+
+    let response = client
+        .whoami()
+        .await
+        .context("The homeserver rejected the credentials")?;
+    if let Some(session) = client.matrix_auth().session()
+        && Some(&session.tokens) != tokens.as_ref()
+    {
+        save_session(&session_path, &session)?;
+        info!(path = %session_path.display(), "Access token refreshed, saved session");
+    }
+    if response.user_id.as_str() != config.username {
+        return Err(anyhow::anyhow!(
+            "Credentials belong to {}, but 'username' is {}",
+            response.user_id,
+            config.username
+        ));
+    }
+    println!(
+        "Credentials are valid for {} (device {})",
+        response.user_id,
+        response
+            .device_id
+            .as_ref()
+            .map_or("unknown", |device_id| device_id.as_str())
+    );
+    Ok(())
+}
+
+/// Log in using a persisted session, the configured access token, or a password.
+///
+/// A session saved by an earlier password login or token refresh is preferred,
+/// since it holds the most recent tokens. Otherwise the configured access token
+/// is used, and only if there is none the bot logs in with its password and
+/// saves the new session for later starts. With `relogin` set, the saved session
+/// is skipped and a password login is done, reusing the saved device ID.
+async fn log_in(client: &Client, config: &Config, relogin: bool) -> Result<()> {
+    let session_path = config.session_path();
+
+    let mut saved_session = if session_path.exists() && !relogin {
+        Some(load_session(&session_path)?)
+    } else {
+        None
+    };
+    if let Some(ref session) = saved_session
+        && let Some(ref access_token) = config.access_token
+        && session.tokens.access_token != *access_token
+    {
+        if configured_token_replaces(session, access_token, config.refresh_token.as_deref()) {
+            warn!(
+                path = %session_path.display(),
+                "Configured access token differs from the saved session, using the configured one"
+            );
+            saved_session = None;
+        } else {
+            warn!(
+                path = %session_path.display(),
+                "Configured access token differs from the saved session, using the saved one since its tokens may have been refreshed"
+            );
+        }
+    }
+
+    if let Some(session) = saved_session {
+        info!(
+            device_id = %session.meta.device_id,
+            path = %session_path.display(),
+            "Restoring session"
+        );
+        client
+            .matrix_auth()
+            .restore_session(session, matrix_sdk::store::RoomLoadSettings::default())
+            .await?;
+    } else if let Some(ref access_token) = config.access_token
+        && !relogin
+    {
+        // Create a MatrixSession with existing access token
+        let user_id = UserId::parse(&config.username)
+            .map_err(|e| anyhow::anyhow!("Invalid user ID '{}': {}", config.username, e))?;
+
+        let session = MatrixSession {
+            meta: SessionMeta {
+                user_id,
+                device_id: config.device_id.as_str().into(),
+            },
+            tokens: SessionTokens {
+                access_token: access_token.clone(),
+                refresh_token: config.refresh_token.clone(),
+            },
+        };
+
+        // Restore the session with access token
+        client
+            .matrix_auth()
+            .restore_session(session, matrix_sdk::store::RoomLoadSettings::default())
+            .await?;
+    } else if let Some(ref password) = config.password {
+        // Keep the device ID of a previous session so the bot doesn't pile up devices
+        let previous_device_id = if session_path.exists() {
+            load_session(&session_path)
+                .ok()
+                .map(|session| session.meta.device_id)
+        } else {
+            None
+        };
+
+        info!(user_id = %config.username, "Logging in with password");
+        let mut login = client
+            .matrix_auth()
+            .login_username(&config.username, password)
+            .initial_device_display_name("matrix-bot-help")
+            .request_refresh_token();
+        if let Some(ref device_id) = previous_device_id {
+            login = login.device_id(device_id.as_str());
+        }
+        // Only rejected credentials exit with EXIT_AUTH_FAILURE, an unreachable
+        // homeserver is an ordinary error
+        login.send().await.map_err(|e| -> anyhow::Error {
+            if is_rejected_credentials(&e) {
+                AuthenticationError {
+                    after_login: false,
+                    message: format!("password login failed: {}", e),
+                }
+                .into()
+            } else {
+                anyhow::Error::new(e).context("Password login failed")
+            }
+        })?;
+
+        let session = client
+            .matrix_auth()
+            .session()
+            .context("Client should have a session after login")?;
+        save_session(&session_path, &session)?;
+        info!(
+            device_id = %session.meta.device_id,
+            path = %session_path.display(),
+            "Saved session"
+        );
+    } else {
+        return Err(AuthenticationError {
+            after_login: false,
+            message: "no password configured to log in again".to_string(),
+        }
+        .into());
+    }
 
     Ok(())
 }
 
+#[instrument(skip_all, fields(%sender, %flow_id))]
+async fn on_verification_request(
+    client: Client,
+    sender: OwnedUserId,
+    flow_id: String,
+    admins: &[String],
+) {
+    if !is_verification_allowed(sender.as_str(), admins) {
+        info!("Ignoring verification request from non-admin");
+        return;
+    }
+
+    let Some(request) = client
+        .encryption()
+        .get_verification_request(&sender, &flow_id)
+        .await
+    else {
+        warn!("Verification request not found");
+        return;
+    };
+
+    tokio::spawn(accept_verification_request(request).in_current_span());
+}
+
 async fn cleanup_welcomed_users(
     welcomed_users: Arc<RwLock<std::collections::HashSet<(String, Instant)>>>,
     timeout_seconds: u64,
@@ -218,46 +1362,468 @@ async fn cleanup_welcomed_users(
     });
 }
 
+/// Leave (and optionally forget) joined rooms that housekeeping considers unused.
+///
+/// Rooms without recorded activity are treated as idle since tracking started, which
+/// is kept in the activity file across restarts along with the last activity per room.
+#[instrument(name = "housekeeping", skip_all)]
+async fn run_housekeeping(
+    client: &Client,
+    config: &HousekeepingConfig,
+    room_activity: &RwLock<RoomActivity>,
+) {
+    let rooms = client.joined_rooms();
+    let now = SystemTime::now();
+    let mut left = 0;
+
+    for room in &rooms {
+        let snapshot = RoomSnapshot {
+            room_id: room.room_id().as_str(),
+            joined_members: room.joined_members_count(),
+            invited_members: room.invited_members_count(),
+            idle: room_activity
+                .read()
+                .await
+                .idle(room.room_id().as_str(), now),
+        };
+
+        let Some(reason) = leave_reason(&snapshot, config) else {
+            debug!(
+                room_id = %room.room_id(),
+                reason = %keep_reason(&snapshot, config),
+                joined_members = snapshot.joined_members,
+                invited_members = snapshot.invited_members,
+                "Keeping room"
+            );
+            continue;
+        };
+
+        info!(room_id = %room.room_id(), %reason, "Leaving room");
+        if let Err(e) = room.leave().await {
+            error!(room_id = %room.room_id(), error = %e, "Failed to leave room");
+            continue;
+        }
+        left += 1;
+
+        if config.forget_rooms {
+            match room.forget().await {
+                Ok(()) => info!(room_id = %room.room_id(), "Forgot room"),
+                Err(e) => error!(room_id = %room.room_id(), error = %e, "Failed to forget room"),
+            }
+        }
+    }
+
+    // Forget rooms that were left, also by kicks or bans, so the file doesn't keep growing
+    let joined: HashSet<String> = client
+        .joined_rooms()
+        .iter()
+        .map(|room| room.room_id().to_string())
+        .collect();
+    let mut activity = room_activity.write().await;
+    activity.retain(|room_id| joined.contains(room_id));
+    if let Err(e) = activity.save() {
+        error!(error = %e, "Failed to save room activity");
+    }
+
+    info!(checked = rooms.len(), left, "Checked joined rooms");
+}
+
+#[instrument(skip_all, fields(
+    room_id = %room.room_id(),
+    sender = %event.sender,
+    event_id = %event.event_id,
+))]
 async fn on_room_message(
     event: OriginalSyncRoomMessageEvent,
     room: Room,
-    help_text: &str,
+    context: &EventContext,
     bot_user_id: &UserId,
-    bot_filtering: &matrix_bot_help::BotFilteringConfig,
-    help_format: &HelpFormat,
 ) {
     // Only respond to messages in joined rooms
     if room.state() != RoomState::Joined {
         return;
     }
 
-    let MessageType::Text(text_content) = event.content.msgtype else {
+    // Never answer messages sent before the bot started
+    if event.origin_server_ts < context.started_at {
+        return;
+    }
+
+    // In encrypted rooms the SDK decrypts messages before this handler runs,
+    // so the content is always plaintext here
+    let MessageType::Text(ref text_content) = event.content.msgtype else {
         return;
     };
 
+    let settings = context.settings();
+
+    // Admin commands are handled before bot filtering, so that admins can't
+    // lock themselves out with `!bot ignore`
+    if let Some(command) = parse_admin_command(&text_content.body) {
+        if event.sender != bot_user_id {
+            on_admin_command(command, &event.sender, &room, context, &settings).await;
+        }
+        return;
+    }
+
     // Check if sender should be ignored based on bot filtering configuration
-    if should_ignore_user(event.sender.as_str(), bot_user_id.as_str(), bot_filtering) {
-        println!("Ignoring message from filtered user: {}", event.sender);
+    if context.is_ignored(&settings, event.sender.as_str(), bot_user_id.as_str()) {
+        debug!("Ignoring message from filtered user");
+        context.metrics.ignored_message();
         return;
     }
 
-    // Check if message starts with help command
-    if text_content.body.starts_with("!help") {
-        println!("Received help request in room {}", room.room_id());
+    // Answer the help command, custom commands and frequently asked questions,
+    // counted by the topic that was answered
+    let (topic, response, delivery, faq) = if is_help_command(&text_content.body) {
+        let response = render_message(&settings.help_text, &settings.help_format);
+        ("help", response, &Delivery::Room, None)
+    } else if let Some(command) = find_command(&settings.commands, &text_content.body) {
+        let response = render_message(&command.response, &command.config.format);
+        (
+            command.config.name.as_str(),
+            response,
+            &command.config.delivery,
+            None,
+        )
+    } else if let Some(faq) =
+        match_faq(&room, &event.sender, &text_content.body, context, &settings)
+        && let Some(response) = settings.topic_response(&faq.topic)
+    {
+        // Replying makes clear which message is answered, since nobody asked the bot
+        (faq.topic.as_str(), response, &Delivery::Reply, Some(faq))
+    } else {
+        return;
+    };
+    info!(topic, "Received help request");
 
-        let response = match help_format {
-            HelpFormat::Plain => RoomMessageEventContent::text_plain(help_text),
-            HelpFormat::Html => RoomMessageEventContent::text_html(help_text, help_text),
-            HelpFormat::Markdown => RoomMessageEventContent::text_markdown(help_text),
-        };
+    let sending = Instant::now();
+    match send_response(&room, &event, response, delivery).await {
+        Ok(()) => {
+            context.metrics.send_duration("help", sending.elapsed());
+            context.metrics.help_request(room.room_id().as_str(), topic);
+            if let Some(faq) = faq {
+                context.metrics.faq_answer(&faq.name);
+            }
+            context.record_stats(|stats| {
+                stats.help_request(room.room_id().as_str(), topic, event.sender.as_str())
+            });
+        }
+        Err(e) => {
+            error!(
+                topic,
+                error = format!("{:#}", e),
+                "Failed to send help message"
+            );
+            // Answer the question again when it is asked again
+            if let Some(faq) = faq {
+                context
+                    .faq_cooldowns
+                    .lock()
+                    .expect("FAQ cooldowns lock poisoned")
+                    .forget(room.room_id().as_str(), &faq.name);
+            }
+        }
+    }
+}
 
-        if let Err(e) = room.send(response).await {
-            eprintln!("Failed to send help message: {}", e);
+/// Find the frequently asked question a message asks, unless it was answered in the room recently.
+///
+/// The answer is recorded for the cooldown right away, and forgotten again if sending it fails.
+fn match_faq<'a>(
+    room: &Room,
+    sender: &UserId,
+    body: &str,
+    context: &EventContext,
+    settings: &'a Settings,
+) -> Option<&'a FaqEntry> {
+    let faq = settings.faq.as_ref()?;
+    // Never answer the bot's own answers, even if `ignore_self` is off
+    if !faq.answers_in(room.room_id().as_str()) || room.own_user_id() == sender {
+        return None;
+    }
+    let entry = faq.find(body)?;
+    let answer = context
+        .faq_cooldowns
+        .lock()
+        .expect("FAQ cooldowns lock poisoned")
+        .try_answer(
+            room.room_id().as_str(),
+            &entry.name,
+            faq.cooldown,
+            Instant::now(),
+        );
+    if !answer {
+        debug!(faq = %entry.name, "Not answering FAQ again during its cooldown");
+        return None;
+    }
+    info!(faq = %entry.name, topic = %entry.topic, "Answering frequently asked question");
+    Some(entry)
+}
+
+/// Send the response to a command as configured by its delivery.
+async fn send_response(
+    room: &Room,
+    event: &OriginalSyncRoomMessageEvent,
+    response: RoomMessageEventContent,
+    delivery: &Delivery,
+) -> Result<()> {
+    match delivery {
+        Delivery::Room => {
+            room.send(response).await?;
+        }
+        Delivery::Reply => {
+            let reply = response.make_reply_to(event, ForwardThread::Yes, AddMentions::Yes);
+            room.send(reply).await?;
+        }
+        Delivery::Direct => {
+            let client = room.client();
+            let direct_room = match client.get_dm_room(&event.sender) {
+                Some(direct_room) => direct_room,
+                None => {
+                    info!("Creating direct message room");
+                    client
+                        .create_dm(&event.sender)
+                        .await
+                        .context("Failed to create direct message room")?
+                }
+            };
+            direct_room.send(response).await?;
         }
     }
+    Ok(())
+}
+
+/// A room's name and ID for messages, e.g. `Support (!abc:example.com)`.
+fn room_label(room: &Room) -> String {
+    match room.cached_display_name() {
+        Some(name) => format!("{} ({})", name, room.room_id()),
+        None => room.room_id().to_string(),
+    }
+}
+
+/// Whether a user may use admin commands.
+///
+/// Admins are listed in `admins`, or have at least `admin_power_level` in `admin_room`.
+async fn is_admin(room: &Room, settings: &Settings, user_id: &UserId) -> bool {
+    if settings
+        .admins
+        .iter()
+        .any(|admin| admin == user_id.as_str())
+    {
+        return true;
+    }
+    let Some(admin_room) = settings
+        .admin_room
+        .as_deref()
+        .and_then(|admin_room| RoomId::parse(admin_room).ok())
+        .and_then(|admin_room| room.client().get_room(&admin_room))
+    else {
+        return false;
+    };
+    match admin_room.get_member(user_id).await {
+        Ok(Some(member)) => {
+            *member.membership() == MembershipState::Join
+                && member.power_level()
+                    >= UserPowerLevel::Int(Int::new_saturating(settings.admin_power_level))
+        }
+        Ok(None) => false,
+        Err(e) => {
+            warn!(error = %e, "Failed to look up power level in admin room");
+            false
+        }
+    }
+}
+
+/// Run an admin command from an admin and reply in the room it was sent in.
+///
+/// Commands from anyone else are ignored without a reply.
+async fn on_admin_command(
+    command: std::result::Result<AdminCommand, String>,
+    sender: &UserId,
+    room: &Room,
+    context: &EventContext,
+    settings: &Settings,
+) {
+    if !is_admin(room, settings, sender).await {
+        debug!("Ignoring admin command from non-admin");
+        return;
+    }
+    let reply = match command {
+        Ok(command) => {
+            info!(?command, "Received admin command");
+            run_admin_command(command, room, context).await
+        }
+        Err(usage) => Some(usage),
+    };
+    if let Some(reply) = reply
+        && let Err(e) = room
+            .send(RoomMessageEventContent::notice_plain(reply))
+            .await
+    {
+        error!(error = %e, "Failed to reply to admin command");
+    }
 }
 
-async fn on_stripped_state_member(event: StrippedRoomMemberEvent, client: Client, room: Room) {
+/// Most rooms listed by `!bot stats`, to keep the reply readable.
+const MAX_STATS_ROOMS: usize = 20;
+
+/// Run an admin command, returning the reply if there is one.
+async fn run_admin_command(
+    command: AdminCommand,
+    room: &Room,
+    context: &EventContext,
+) -> Option<String> {
+    let client = room.client();
+    let reply = match command {
+        AdminCommand::Help => ADMIN_HELP.to_string(),
+        AdminCommand::Status => {
+            let settings = context.settings();
+            let sync = match context.health.readiness() {
+                Readiness::Ready => "syncing".to_string(),
+                Readiness::NotReady(reason) => reason,
+            };
+            [
+                format!(
+                    "matrix-bot-help {}, up {}",
+                    env!("CARGO_PKG_VERSION"),
+                    format_uptime(context.started.elapsed())
+                ),
+                format!(
+                    "Rooms: {} joined, {} invited",
+                    client.joined_rooms().len(),
+                    client.invited_rooms().len()
+                ),
+                format!("Sync: {}", sync),
+                format!(
+                    "Ignored users: {} in config, {} with !bot ignore",
+                    settings.bot_filtering.ignored_users.len(),
+                    context.ignore_list().len()
+                ),
+            ]
+            .join("\n")
+        }
+        AdminCommand::Rooms => {
+            let mut rooms = client.joined_rooms();
+            rooms.sort_by(|a, b| a.room_id().cmp(b.room_id()));
+            let mut reply = format!("Joined rooms ({}):", rooms.len());
+            for joined in rooms {
+                reply.push_str(&format!(
+                    "\n- {}, {} members",
+                    room_label(&joined),
+                    joined.joined_members_count()
+                ));
+            }
+            reply
+        }
+        AdminCommand::Stats => match context.stats {
+            Some(ref stats) => {
+                let report = stats.lock().expect("Stats lock poisoned").report();
+                report.to_text(MAX_STATS_ROOMS, |room_id| {
+                    RoomId::parse(room_id)
+                        .ok()
+                        .and_then(|room_id| client.get_room(&room_id))
+                        .and_then(|room| room.cached_display_name())
+                        .map(|name| name.to_string())
+                })
+            }
+            None => {
+                "Usage statistics are disabled, enable them with [stats] enabled = true".to_string()
+            }
+        },
+        AdminCommand::Leave(target) => {
+            let room_id = match OwnedRoomId::try_from(target.clone()) {
+                Ok(room_id) => room_id,
+                Err(alias) => match client.resolve_room_alias(&alias).await {
+                    Ok(response) => response.room_id,
+                    Err(e) => return Some(format!("Failed to resolve {}: {}", alias, e)),
+                },
+            };
+            let Some(leaving) = client.get_room(&room_id).filter(|leaving| {
+                matches!(leaving.state(), RoomState::Joined | RoomState::Invited)
+            }) else {
+                return Some(format!("The bot is not in {}", target));
+            };
+            // Reply first when leaving this room, since the reply can't be sent afterwards
+            let here = room_id == room.room_id();
+            if here
+                && let Err(e) = room
+                    .send(RoomMessageEventContent::notice_plain("Leaving this room"))
+                    .await
+            {
+                error!(error = %e, "Failed to reply to admin command");
+            }
+            match leaving.leave().await {
+                Ok(()) => {
+                    info!(left_room_id = %room_id, "Left room on admin command");
+                    if here {
+                        return None;
+                    }
+                    format!("Left {}", target)
+                }
+                Err(e) => {
+                    error!(left_room_id = %room_id, error = %e, "Failed to leave room");
+                    format!("Failed to leave {}: {}", target, e)
+                }
+            }
+        }
+        AdminCommand::Ignore(user_id) => match context.ignore_list().insert(user_id.as_str()) {
+            Ok(true) => format!("Ignoring {} from now on", user_id),
+            Ok(false) => format!("{} is already ignored", user_id),
+            Err(e) => {
+                error!(error = format!("{:#}", e), "Failed to save ignore list");
+                format!("Failed to save the ignore list: {:#}", e)
+            }
+        },
+        AdminCommand::Unignore(user_id) => match context.ignore_list().remove(user_id.as_str()) {
+            Ok(true) => format!("No longer ignoring {}", user_id),
+            Ok(false)
+                if context
+                    .settings()
+                    .bot_filtering
+                    .ignored_users
+                    .contains(&user_id.to_string()) =>
+            {
+                format!(
+                    "{} is ignored in the config file, remove them from 'bot_filtering.ignored_users' instead",
+                    user_id
+                )
+            }
+            Ok(false) => format!("{} is not ignored", user_id),
+            Err(e) => {
+                error!(error = format!("{:#}", e), "Failed to save ignore list");
+                format!("Failed to save the ignore list: {:#}", e)
+            }
+        },
+        AdminCommand::Reload => {
+            let config_path = context.config_path.to_string_lossy();
+            match load_config(&config_path).and_then(|config| Settings::load(&config)) {
+                Ok(settings) => {
+                    *context.settings.write().expect("Settings lock poisoned") = Arc::new(settings);
+                    info!("Reloaded settings");
+                    "Reloaded help and welcome texts, bot filtering, join detection and admins. \
+                     Other settings only change on restart."
+                        .to_string()
+                }
+                Err(e) => {
+                    warn!(error = format!("{:#}", e), "Failed to reload settings");
+                    format!("Reload failed, keeping the previous settings: {:#}", e)
+                }
+            }
+        }
+    };
+    Some(reply)
+}
+
+#[instrument(skip_all, fields(room_id = %room.room_id(), sender = %event.sender))]
+async fn on_stripped_state_member(
+    event: StrippedRoomMemberEvent,
+    client: Client,
+    room: Room,
+    join_retry: &RetryPolicy,
+    shutdown: &Shutdown,
+    context: &EventContext,
+) {
     // Only process invitations for the bot itself
     if event.state_key != client.user_id().expect("Client should have a user ID") {
         return;
@@ -265,42 +1831,91 @@ async fn on_stripped_state_member(event: StrippedRoomMemberEvent, client: Client
 
     // Check if this is an invitation
     if event.content.membership == MembershipState::Invite {
-        println!("Received invitation to room {}", room.room_id());
+        info!("Received invitation");
+        if let Some(ref notifications) = context.notifications {
+            notifications.invite(format!(
+                "Invited to {} by {}",
+                room_label(&room),
+                event.sender
+            ));
+        }
 
         // Join the room with retry logic
-        let room_id = room.room_id().to_owned();
-        tokio::spawn(async move {
-            let mut delay = 2;
-
-            while let Err(e) = room.join().await {
-                eprintln!(
-                    "Failed to join room {} ({}), retrying in {}s",
-                    room_id, e, delay
+        let policy = join_retry.clone();
+        let shutdown = shutdown.clone();
+        let context = context.clone();
+        tokio::spawn(
+            async move {
+                let join = retry_with_policy(
+                    &policy,
+                    || room.join(),
+                    |attempt, e, delay| {
+                        warn!(
+                            attempt,
+                            max_attempts = policy.max_attempts,
+                            error = %e,
+                            delay_seconds = delay.as_secs_f64(),
+                            "Failed to join room, retrying"
+                        );
+                    },
                 );
-                tokio::time::sleep(tokio::time::Duration::from_secs(delay)).await;
-                delay *= 2;
 
-                if delay > 3600 {
-                    eprintln!("Can't join room {} after multiple retries", room_id);
-                    break;
-                }
-            }
+                // Retries can take hours, so don't hold up a shutdown for them
+                let result = tokio::select! {
+                    result = join => result,
+                    _ = shutdown.triggered() => {
+                        info!("Stopped joining room because of shutdown");
+                        return;
+                    }
+                };
 
-            if (room.join().await).is_ok() {
-                println!("Successfully joined room {}", room_id);
+                match result {
+                    Ok(()) => {
+                        info!("Successfully joined room");
+                        context.metrics.invite(InviteOutcome::Accepted);
+                        if let Some(ref notifications) = context.notifications {
+                            notifications.invite(format!("Joined {}", room_label(&room)));
+                        }
+                    }
+                    Err(e) => {
+                        error!(
+                            attempts = policy.max_attempts,
+                            error = %e,
+                            "Giving up on joining room"
+                        );
+                        let mut outcome = InviteOutcome::Failed;
+                        if policy.on_give_up == GiveUpAction::Decline {
+                            match room.leave().await {
+                                Ok(()) => {
+                                    info!("Declined invitation");
+                                    outcome = InviteOutcome::Rejected;
+                                }
+                                Err(e) => error!(error = %e, "Failed to decline invitation"),
+                            }
+                        }
+                        context.metrics.invite(outcome);
+                    }
+                }
             }
-        });
+            .in_current_span(),
+        );
     }
 }
 
+#[instrument(skip_all, fields(
+    room_id = %room.room_id(),
+    sender = %event.sender(),
+    event_id = %event.event_id(),
+))]
 async fn on_room_member(
     event: SyncRoomMemberEvent,
     room: Room,
-    join_detection_config: &matrix_bot_help::JoinDetectionConfig,
-    bot_filtering: &matrix_bot_help::BotFilteringConfig,
+    context: &EventContext,
     welcomed_users: Arc<RwLock<std::collections::HashSet<(String, Instant)>>>,
-    welcome_text: &Option<String>,
 ) {
+    let settings = context.settings();
+    let join_detection_config = &settings.join_detection;
+
     // Check if join detection is enabled
     if !join_detection_config.enabled {
         return;
@@ -311,6 +1926,11 @@ async fn on_room_member(
         return;
     }
 
+    // Don't welcome users who joined before the bot started
+    if event.origin_server_ts() < context.started_at {
+        return;
+    }
+
     // Check if this room is in the monitored list (if list is not empty)
     if !join_detection_config.monitored_rooms.is_empty() {
         let room_id_str = room.room_id().to_string();
@@ -332,8 +1952,8 @@ async fn on_room_member(
     }
 
     // Check if user should be ignored based on bot filtering configuration
-    if should_ignore_user(user_id.as_str(), bot_user_id.as_str(), bot_filtering) {
-        println!("Ignoring join event from filtered user: {}", user_id);
+    if context.is_ignored(&settings, user_id.as_str(), bot_user_id.as_str()) {
+        debug!(%user_id, "Ignoring join event from filtered user");
         return;
     }
 
@@ -349,7 +1969,10 @@ async fn on_room_member(
                     return;
                 }
 
-                println!("User {} joined room {}", user_id, room.room_id());
+                info!(%user_id, "User joined room");
+                if let Some(ref notifications) = context.notifications {
+                    notifications.join(format!("{} joined {}", user_id, room_label(&room)));
+                }
 
                 // Check if we've already welcomed this user in this room recently
                 let user_room_key = format!("{}:{}", user_id, room.room_id());
@@ -367,44 +1990,32 @@ async fn on_room_member(
 
                     // Check if this user-room combination exists after cleanup
                     if users.iter().any(|(key, _)| key == &user_room_key) {
-                        println!(
-                            "Already welcomed {} in room {} recently, skipping",
-                            user_id,
-                            room.room_id()
-                        );
+                        info!(%user_id, "Already welcomed user recently, skipping");
+                        context.metrics.welcome(WelcomeOutcome::Deduplicated);
                         return;
                     }
                 }
 
                 // Send welcome message if enabled
                 if join_detection_config.send_welcome {
-                    // Combine welcome_message with welcome_text from file if both exist
-                    let welcome_content = if let Some(file_text) = welcome_text {
-                        format!("{}\n{}", join_detection_config.welcome_message, file_text)
-                    } else {
-                        join_detection_config.welcome_message.clone()
-                    };
-                    // Create a personalized welcome message mentioning the user
-                    let welcome_message = format!("{}: {}", user_id, welcome_content);
-                    let response = match join_detection_config.welcome_format {
-                        HelpFormat::Plain => RoomMessageEventContent::text_plain(&welcome_message),
-                        HelpFormat::Html => {
-                            RoomMessageEventContent::text_html(&welcome_message, &welcome_message)
-                        }
-                        HelpFormat::Markdown => {
-                            RoomMessageEventContent::text_markdown(&welcome_message)
-                        }
-                    };
+                    let welcome_message = welcome_message_text(
+                        join_detection_config,
+                        settings.welcome_text.as_deref(),
+                        user_id.as_str(),
+                    );
+                    let response =
+                        render_message(&welcome_message, &join_detection_config.welcome_format);
 
                     // Send welcome message in the room where the user joined
+                    let sending = Instant::now();
                     if let Err(e) = room.send(response).await {
-                        eprintln!("Failed to send welcome message to {}: {}", user_id, e);
+                        error!(%user_id, error = %e, "Failed to send welcome message");
+                        context.metrics.welcome(WelcomeOutcome::Failed);
                     } else {
-                        println!(
-                            "Sent welcome message to {} in room {}",
-                            user_id,
-                            room.room_id()
-                        );
+                        info!(%user_id, "Sent welcome message");
+                        context.metrics.send_duration("welcome", sending.elapsed());
+                        context.metrics.welcome(WelcomeOutcome::Sent);
+                        context.record_stats(|stats| stats.welcome(room.room_id().as_str()));
 
                         // Add this user-room combination to the welcomed set with timestamp
                         let mut users = welcomed_users.write().await;
@@ -0,0 +1,325 @@
+use prometheus::core::Collector;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, Opts, Registry, TextEncoder,
+};
+use serde::Deserialize;
+use std::time::Duration;
+
+/// Configuration for the Prometheus metrics endpoint.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// Whether to serve metrics at all
+    pub enabled: bool,
+    /// Address and port to listen on
+    pub listen_address: String,
+    /// Path the metrics are served at
+    pub path: String,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            listen_address: "127.0.0.1:9184".to_string(),
+            path: "/metrics".to_string(),
+        }
+    }
+}
+
+/// Outcome of a welcome for a user who joined.
+#[derive(Debug, Clone, Copy)]
+pub enum WelcomeOutcome {
+    Sent,
+    /// The user was already welcomed in the room recently
+    Deduplicated,
+    Failed,
+}
+
+/// Outcome of an invite to a room.
+#[derive(Debug, Clone, Copy)]
+pub enum InviteOutcome {
+    /// The bot joined the room
+    Accepted,
+    /// Joining failed on every attempt
+    Failed,
+    /// Joining failed and the invite was declined
+    Rejected,
+}
+
+/// Totals of the main counters, to report on what happened over a period.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct MetricTotals {
+    pub help_requests: u64,
+    pub welcomes: u64,
+    pub invites_accepted: u64,
+    /// Invites that failed, including declined ones
+    pub invites_failed: u64,
+    pub sync_errors: u64,
+}
+
+impl MetricTotals {
+    /// What was counted after `earlier` was taken.
+    pub fn since(&self, earlier: &MetricTotals) -> MetricTotals {
+        MetricTotals {
+            help_requests: self.help_requests.saturating_sub(earlier.help_requests),
+            welcomes: self.welcomes.saturating_sub(earlier.welcomes),
+            invites_accepted: self
+                .invites_accepted
+                .saturating_sub(earlier.invites_accepted),
+            invites_failed: self.invites_failed.saturating_sub(earlier.invites_failed),
+            sync_errors: self.sync_errors.saturating_sub(earlier.sync_errors),
+        }
+    }
+}
+
+/// Counters and timings of what the bot does, in Prometheus format.
+///
+/// Cloning is cheap, and all clones record into the same metrics.
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Registry,
+    help_requests: IntCounterVec,
+    welcomes: IntCounterVec,
+    invites: IntCounterVec,
+    faq_answers: IntCounterVec,
+    ignored_messages: IntCounter,
+    sync_errors: IntCounter,
+    reconnects: IntCounter,
+    send_duration: HistogramVec,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new_custom(Some("matrix_bot_help".to_string()), None)
+            .expect("Metrics prefix should be valid");
+
+        let help_requests = IntCounterVec::new(
+            Opts::new("help_requests_total", "Help requests answered"),
+            &["room", "topic"],
+        )
+        .expect("Metric should be valid");
+        let welcomes = IntCounterVec::new(
+            Opts::new(
+                "welcomes_total",
+                "Welcomes for users who joined, by outcome",
+            ),
+            &["outcome"],
+        )
+        .expect("Metric should be valid");
+        let invites = IntCounterVec::new(
+            Opts::new("invites_total", "Room invites, by outcome"),
+            &["outcome"],
+        )
+        .expect("Metric should be valid");
+        let faq_answers = IntCounterVec::new(
+            Opts::new(
+                "faq_answers_total",
+                "Frequently asked questions answered without a command, by FAQ",
+            ),
+            &["faq"],
+        )
+        .expect("Metric should be valid");
+        let ignored_messages = IntCounter::new(
+            "ignored_messages_total",
+            "Messages ignored because of bot filtering, including the bot's own",
+        )
+        .expect("Metric should be valid");
+        let sync_errors = IntCounter::new("sync_errors_total", "Failed syncs that were retried")
+            .expect("Metric should be valid");
+        let reconnects = IntCounter::new(
+            "reconnects_total",
+            "Successful syncs after one or more failed ones",
+        )
+        .expect("Metric should be valid");
+        let send_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "send_duration_seconds",
+                "Time taken to send a message, by kind of message",
+            ),
+            &["message"],
+        )
+        .expect("Metric should be valid");
+
+        for metric in [
+            Box::new(help_requests.clone()) as Box<dyn Collector>,
+            Box::new(welcomes.clone()),
+            Box::new(invites.clone()),
+            Box::new(faq_answers.clone()),
+            Box::new(ignored_messages.clone()),
+            Box::new(sync_errors.clone()),
+            Box::new(reconnects.clone()),
+            Box::new(send_duration.clone()),
+        ] {
+            registry
+                .register(metric)
+                .expect("Metric should only be registered once");
+        }
+
+        // Outcomes are known up front, so they show up as zero before they happen
+        for outcome in ["sent", "deduplicated", "failed"] {
+            welcomes.with_label_values(&[outcome]);
+        }
+        for outcome in ["accepted", "failed", "rejected"] {
+            invites.with_label_values(&[outcome]);
+        }
+
+        Self {
+            registry,
+            help_requests,
+            welcomes,
+            invites,
+            faq_answers,
+            ignored_messages,
+            sync_errors,
+            reconnects,
+            send_duration,
+        }
+    }
+
+    /// Count a help request answered in a room.
+    ///
+    /// The topic is the command that was answered, e.g. `help` or a custom command.
+    pub fn help_request(&self, room_id: &str, topic: &str) {
+        self.help_requests
+            .with_label_values(&[room_id, topic])
+            .inc();
+    }
+
+    pub fn welcome(&self, outcome: WelcomeOutcome) {
+        let label = match outcome {
+            WelcomeOutcome::Sent => "sent",
+            WelcomeOutcome::Deduplicated => "deduplicated",
+            WelcomeOutcome::Failed => "failed",
+        };
+        self.welcomes.with_label_values(&[label]).inc();
+    }
+
+    pub fn invite(&self, outcome: InviteOutcome) {
+        let label = match outcome {
+            InviteOutcome::Accepted => "accepted",
+            InviteOutcome::Failed => "failed",
+            InviteOutcome::Rejected => "rejected",
+        };
+        self.invites.with_label_values(&[label]).inc();
+    }
+
+    /// Count a frequently asked question answered, which also counts as a help request.
+    pub fn faq_answer(&self, faq: &str) {
+        self.faq_answers.with_label_values(&[faq]).inc();
+    }
+
+    pub fn ignored_message(&self) {
+        self.ignored_messages.inc();
+    }
+
+    pub fn sync_error(&self) {
+        self.sync_errors.inc();
+    }
+
+    pub fn reconnect(&self) {
+        self.reconnects.inc();
+    }
+
+    /// Record how long sending a message took, e.g. `help` or `welcome`.
+    pub fn send_duration(&self, message: &str, duration: Duration) {
+        self.send_duration
+            .with_label_values(&[message])
+            .observe(duration.as_secs_f64());
+    }
+
+    /// Totals of the main counters since the bot started.
+    pub fn totals(&self) -> MetricTotals {
+        let help_requests = self
+            .help_requests
+            .collect()
+            .iter()
+            .flat_map(|family| family.get_metric())
+            .map(|metric| metric.get_counter().get_value() as u64)
+            .sum();
+        let invites = |outcome: &str| self.invites.with_label_values(&[outcome]).get();
+        MetricTotals {
+            help_requests,
+            welcomes: self.welcomes.with_label_values(&["sent"]).get(),
+            invites_accepted: invites("accepted"),
+            invites_failed: invites("failed") + invites("rejected"),
+            sync_errors: self.sync_errors.get(),
+        }
+    }
+
+    /// All metrics in the Prometheus text format.
+    pub fn encode(&self) -> String {
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .expect("Encoding metrics into memory should not fail");
+        String::from_utf8(buffer).expect("Metrics should be valid UTF-8")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metrics_encoding() {
+        // Given some recorded events
+        let metrics = Metrics::new();
+        metrics.help_request("!room:example.com", "help");
+        metrics.help_request("!room:example.com", "help");
+        metrics.welcome(WelcomeOutcome::Deduplicated);
+        metrics.invite(InviteOutcome::Rejected);
+        metrics.sync_error();
+        metrics.faq_answer("password-reset");
+        metrics.send_duration("help", Duration::from_millis(120));
+
+        // When encoding the metrics
+        let text = metrics.encode();
+
+        // Then they should appear with their prefix and labels
+        assert!(text.contains(
+            "matrix_bot_help_help_requests_total{room=\"!room:example.com\",topic=\"help\"} 2"
+        ));
+        assert!(text.contains("matrix_bot_help_welcomes_total{outcome=\"deduplicated\"} 1"));
+        assert!(text.contains("matrix_bot_help_invites_total{outcome=\"rejected\"} 1"));
+        assert!(text.contains("matrix_bot_help_invites_total{outcome=\"accepted\"} 0"));
+        assert!(text.contains("matrix_bot_help_sync_errors_total 1"));
+        assert!(text.contains("matrix_bot_help_faq_answers_total{faq=\"password-reset\"} 1"));
+        assert!(text.contains("matrix_bot_help_reconnects_total 0"));
+        assert!(text.contains("matrix_bot_help_send_duration_seconds_count{message=\"help\"} 1"));
+    }
+
+    #[test]
+    fn test_metric_totals_since() {
+        // Given totals taken before and after some events
+        let metrics = Metrics::new();
+        metrics.help_request("!room:example.com", "help");
+        let earlier = metrics.totals();
+        metrics.help_request("!room:example.com", "help");
+        metrics.help_request("!other:example.com", "help");
+        metrics.welcome(WelcomeOutcome::Sent);
+        metrics.welcome(WelcomeOutcome::Deduplicated);
+        metrics.invite(InviteOutcome::Rejected);
+
+        // When taking the difference
+        let period = metrics.totals().since(&earlier);
+
+        // Then only the later events should count, across rooms
+        assert_eq!(
+            period,
+            MetricTotals {
+                help_requests: 2,
+                welcomes: 1,
+                invites_accepted: 0,
+                invites_failed: 1,
+                sync_errors: 0,
+            }
+        );
+    }
+}
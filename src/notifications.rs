@@ -0,0 +1,474 @@
+use crate::metrics::{MetricTotals, Metrics};
+use anyhow::{Result, anyhow};
+use matrix_sdk::Client;
+use matrix_sdk::ruma::OwnedRoomId;
+use matrix_sdk::ruma::events::room::message::RoomMessageEventContent;
+use serde::{Deserialize, Deserializer};
+use std::collections::VecDeque;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Subscriber, span, warn};
+use tracing_subscriber::Layer;
+use tracing_subscriber::filter::filter_fn;
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+
+/// Lowest level of log events that are posted to the admin room.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum NotificationLevel {
+    #[default]
+    Warn,
+    Error,
+}
+
+impl NotificationLevel {
+    fn level(&self) -> Level {
+        match self {
+            NotificationLevel::Warn => Level::WARN,
+            NotificationLevel::Error => Level::ERROR,
+        }
+    }
+}
+
+impl FromStr for NotificationLevel {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "warn" | "warning" => Ok(NotificationLevel::Warn),
+            "error" => Ok(NotificationLevel::Error),
+            _ => Err(anyhow!(
+                "Invalid notification level '{}'. Valid options are: warn, error",
+                s
+            )),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for NotificationLevel {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        NotificationLevel::from_str(&s).map_err(serde::de::Error::custom)
+    }
+}
+
+impl fmt::Display for NotificationLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NotificationLevel::Warn => write!(f, "warn"),
+            NotificationLevel::Error => write!(f, "error"),
+        }
+    }
+}
+
+/// Configuration for notifications posted to `admin_room`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NotificationsConfig {
+    /// Whether to post notifications at all
+    pub enabled: bool,
+    /// Lowest level of the bot's log events to post (warn, error)
+    pub level: NotificationLevel,
+    /// Whether to post when the bot is invited to and joins a room
+    pub invites: bool,
+    /// Whether to post when users join rooms with join detection
+    pub joins: bool,
+    /// Post a summary of the past day at this time, in UTC (None = never)
+    pub daily_summary_at: Option<String>,
+    /// Seconds between notification messages, with everything in between sent as one
+    pub batch_seconds: u64,
+    /// Most lines per message; further ones are only counted
+    pub max_lines: usize,
+}
+
+impl Default for NotificationsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            level: NotificationLevel::Warn,
+            invites: true,
+            joins: true,
+            daily_summary_at: None,
+            batch_seconds: 60,
+            max_lines: 20,
+        }
+    }
+}
+
+/// Parse a time of day like `09:00` into seconds after midnight.
+pub fn parse_time_of_day(time: &str) -> Result<u64> {
+    let parsed = time.split_once(':').and_then(|(hours, minutes)| {
+        let hours = hours.parse::<u64>().ok().filter(|hours| *hours < 24)?;
+        let minutes = minutes
+            .parse::<u64>()
+            .ok()
+            .filter(|minutes| *minutes < 60)?;
+        Some(hours * 3600 + minutes * 60)
+    });
+    parsed.ok_or_else(|| anyhow!("Invalid time '{}', expected HH:MM like 09:00", time))
+}
+
+/// Time from `now` until the next time the clock shows `time_of_day` seconds after midnight UTC.
+fn until_time_of_day(time_of_day: u64, now: SystemTime) -> Duration {
+    let since_midnight = now
+        .duration_since(UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_secs() % 86400)
+        .unwrap_or(0);
+    let seconds = (time_of_day + 86400 - since_midnight) % 86400;
+    // Exactly at the time means the next day, so a summary is never sent twice
+    Duration::from_secs(if seconds == 0 { 86400 } else { seconds })
+}
+
+/// Whether log events from `target` are forwarded.
+///
+/// Only the bot's own events are, except those about sending notifications,
+/// which would otherwise feed back into the admin room.
+fn is_forwarded(target: &str) -> bool {
+    (target == "matrix_bot_help" || target.starts_with("matrix_bot_help::"))
+        && !target.starts_with(module_path!())
+}
+
+#[derive(Debug, Default)]
+struct State {
+    pending: VecDeque<String>,
+    /// Lines that didn't fit into the pending batch
+    dropped: usize,
+    warnings: u64,
+    errors: u64,
+    /// Totals at the last summary
+    summarized: MetricTotals,
+}
+
+/// Collects notifications for `admin_room` and posts them in throttled batches.
+///
+/// Cloning is cheap, and all clones share the same queue.
+#[derive(Debug, Clone)]
+pub struct Notifications {
+    config: NotificationsConfig,
+    state: Arc<Mutex<State>>,
+}
+
+impl Notifications {
+    pub fn new(config: &NotificationsConfig) -> Self {
+        Self {
+            config: config.clone(),
+            state: Arc::default(),
+        }
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().expect("Notifications lock poisoned")
+    }
+
+    /// Queue a line for the next batch, or count it if the batch is full.
+    pub fn push(&self, line: String) {
+        let mut state = self.state();
+        if state.pending.len() < self.config.max_lines {
+            state.pending.push_back(line);
+        } else {
+            state.dropped += 1;
+        }
+    }
+
+    /// Report an invite or the room joined because of it, if enabled.
+    pub fn invite(&self, line: String) {
+        if self.config.invites {
+            self.push(line);
+        }
+    }
+
+    /// Report a user joining a room, if enabled.
+    pub fn join(&self, line: String) {
+        if self.config.joins {
+            self.push(line);
+        }
+    }
+
+    fn log_event(&self, level: Level, message: &str, fields: &[String]) {
+        {
+            let mut state = self.state();
+            if level == Level::ERROR {
+                state.errors += 1;
+            } else {
+                state.warnings += 1;
+            }
+        }
+        let line = if fields.is_empty() {
+            format!("{} {}", level, message)
+        } else {
+            format!("{} {} ({})", level, message, fields.join(", "))
+        };
+        self.push(line);
+    }
+
+    /// Take the queued lines as one message, if there are any.
+    fn take_batch(&self) -> Option<String> {
+        let mut state = self.state();
+        if state.pending.is_empty() && state.dropped == 0 {
+            return None;
+        }
+        let mut lines: Vec<String> = state.pending.drain(..).collect();
+        if state.dropped > 0 {
+            lines.push(format!("... and {} more", state.dropped));
+            state.dropped = 0;
+        }
+        Some(lines.join("\n"))
+    }
+
+    /// Summarize what happened since the last summary.
+    fn take_summary(&self, totals: MetricTotals, joined_rooms: usize) -> String {
+        let mut state = self.state();
+        let period = totals.since(&state.summarized);
+        let summary = format!(
+            "Daily summary: {} help requests, {} welcomes, {} invites accepted, {} failed, \
+             {} sync errors, {} warnings, {} errors. In {} rooms.",
+            period.help_requests,
+            period.welcomes,
+            period.invites_accepted,
+            period.invites_failed,
+            period.sync_errors,
+            state.warnings,
+            state.errors,
+            joined_rooms,
+        );
+        state.summarized = totals;
+        state.warnings = 0;
+        state.errors = 0;
+        summary
+    }
+
+    /// A logging layer that queues the bot's warnings and errors.
+    pub fn layer<S>(&self) -> impl Layer<S> + use<S>
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        let level = self.config.level.level();
+        NotificationLayer {
+            notifications: self.clone(),
+        }
+        .with_filter(filter_fn(move |metadata| {
+            is_forwarded(metadata.target()) && (metadata.is_span() || *metadata.level() <= level)
+        }))
+    }
+
+    /// Post queued notifications and daily summaries to the room until the task is dropped.
+    pub async fn run(self, client: Client, room_id: OwnedRoomId, metrics: Metrics) {
+        let mut batches = tokio::time::interval(Duration::from_secs(self.config.batch_seconds));
+        batches.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        let summary_at = self
+            .config
+            .daily_summary_at
+            .as_deref()
+            .and_then(|time| parse_time_of_day(time).ok());
+        // Only polled if a summary time is set
+        let summary = tokio::time::sleep(
+            summary_at
+                .map(|time| until_time_of_day(time, SystemTime::now()))
+                .unwrap_or_default(),
+        );
+        tokio::pin!(summary);
+
+        loop {
+            let message = tokio::select! {
+                _ = batches.tick() => match self.take_batch() {
+                    Some(message) => message,
+                    None => continue,
+                },
+                _ = &mut summary, if summary_at.is_some() => {
+                    let next = until_time_of_day(summary_at.unwrap_or_default(), SystemTime::now());
+                    summary.as_mut().reset(tokio::time::Instant::now() + next);
+                    self.take_summary(metrics.totals(), client.joined_rooms().len())
+                }
+            };
+            let Some(room) = client.get_room(&room_id) else {
+                warn!(%room_id, "Not in the admin room, dropping notifications");
+                continue;
+            };
+            if let Err(e) = room
+                .send(RoomMessageEventContent::notice_plain(message))
+                .await
+            {
+                warn!(%room_id, error = %e, "Failed to send notifications to the admin room");
+            }
+        }
+    }
+}
+
+/// Fields of an event or span as `name=value`, with the message kept apart.
+#[derive(Default)]
+struct FieldVisitor {
+    message: String,
+    fields: Vec<String>,
+}
+
+impl Visit for FieldVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message = value.to_string();
+        } else {
+            self.fields.push(format!("{}={}", field.name(), value));
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            self.message = format!("{:?}", value);
+        } else {
+            self.fields.push(format!("{}={:?}", field.name(), value));
+        }
+    }
+}
+
+/// Fields of a span, kept so that events inside it can be reported with them.
+struct SpanFields(Vec<String>);
+
+struct NotificationLayer {
+    notifications: Notifications,
+}
+
+impl<S> Layer<S> for NotificationLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut visitor = FieldVisitor::default();
+        attrs.record(&mut visitor);
+        span.extensions_mut().insert(SpanFields(visitor.fields));
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let mut fields = Vec::new();
+        if let Some(scope) = ctx.event_scope(event) {
+            for span in scope.from_root() {
+                if let Some(span_fields) = span.extensions().get::<SpanFields>() {
+                    fields.extend(span_fields.0.iter().cloned());
+                }
+            }
+        }
+        let mut visitor = FieldVisitor::default();
+        event.record(&mut visitor);
+        fields.extend(visitor.fields);
+        self.notifications
+            .log_event(*event.metadata().level(), &visitor.message, &fields);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[test]
+    fn test_batches_are_limited() {
+        // Given notifications limited to two lines per message
+        let notifications = Notifications::new(&NotificationsConfig {
+            enabled: true,
+            max_lines: 2,
+            joins: false,
+            ..NotificationsConfig::default()
+        });
+
+        // When queueing more lines than fit, and a disabled kind of line
+        for n in 1..=4 {
+            notifications.push(format!("line {}", n));
+        }
+        notifications.join("@user:example.com joined".to_string());
+        let first = notifications.take_batch();
+        let second = notifications.take_batch();
+
+        // Then one message should have the first lines and count the others
+        assert_eq!(first.as_deref(), Some("line 1\nline 2\n... and 2 more"));
+        assert_eq!(second, None);
+    }
+
+    #[test]
+    fn test_layer_forwards_warnings_with_span_fields() {
+        // Given a subscriber with the notification layer, forwarding warnings
+        let notifications = Notifications::new(&NotificationsConfig::default());
+        let subscriber = tracing_subscriber::registry().with(notifications.layer());
+
+        // When logging at several levels, from the bot and from elsewhere
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!(target: "matrix_bot_help", "handler", room_id = "!room:example.com");
+            let _entered = span.enter();
+            tracing::info!(target: "matrix_bot_help", "Received help request");
+            tracing::warn!(target: "matrix_bot_help", user_id = "@user:example.com", "Failed to send welcome message");
+            tracing::error!(target: "matrix_sdk::sync", "Something in the SDK");
+            tracing::error!(target: "matrix_bot_help::notifications", "Failed to send notifications");
+        });
+
+        // Then only the bot's warning should be queued, with the span's fields
+        assert_eq!(
+            notifications.take_batch().as_deref(),
+            Some(
+                "WARN Failed to send welcome message (room_id=!room:example.com, user_id=@user:example.com)"
+            )
+        );
+    }
+
+    #[test]
+    fn test_summary_covers_period() {
+        // Given a summary taken after one help request and a warning
+        let notifications = Notifications::new(&NotificationsConfig::default());
+        let first_totals = MetricTotals {
+            help_requests: 1,
+            ..MetricTotals::default()
+        };
+        notifications.log_event(Level::WARN, "Sync failed", &[]);
+        let first = notifications.take_summary(first_totals, 3);
+
+        // When taking the next summary after two more help requests
+        let second = notifications.take_summary(
+            MetricTotals {
+                help_requests: 3,
+                ..MetricTotals::default()
+            },
+            3,
+        );
+
+        // Then each should only count its own period
+        assert!(
+            first.starts_with("Daily summary: 1 help requests"),
+            "{}",
+            first
+        );
+        assert!(first.contains("1 warnings"), "{}", first);
+        assert!(
+            second.starts_with("Daily summary: 2 help requests"),
+            "{}",
+            second
+        );
+        assert!(second.contains("0 warnings"), "{}", second);
+    }
+
+    #[test]
+    fn test_time_of_day() {
+        // Given a time of day and moments before, at and after it
+        let nine = parse_time_of_day("09:00").unwrap();
+        let day = |seconds: u64| UNIX_EPOCH + Duration::from_secs(10 * 86400 + seconds);
+
+        // When computing the wait until the next one
+        // Then it should be later today, or tomorrow once passed
+        assert_eq!(nine, 9 * 3600);
+        assert_eq!(
+            until_time_of_day(nine, day(8 * 3600)),
+            Duration::from_secs(3600)
+        );
+        assert_eq!(
+            until_time_of_day(nine, day(9 * 3600)),
+            Duration::from_secs(86400)
+        );
+        assert_eq!(
+            until_time_of_day(nine, day(10 * 3600)),
+            Duration::from_secs(23 * 3600)
+        );
+        assert!(parse_time_of_day("24:00").is_err());
+        assert!(parse_time_of_day("9am").is_err());
+    }
+}
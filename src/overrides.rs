@@ -0,0 +1,354 @@
+use anyhow::{Context, Result, anyhow};
+use std::fs;
+use toml::{Table, Value};
+
+/// Prefix of environment variables that override config values.
+pub const ENV_PREFIX: &str = "MATRIX_BOT_HELP_";
+
+/// Where a config value came from, when it wasn't written in the config file.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ValueSource {
+    /// Set by the named environment variable
+    Env(String),
+    /// Read from the file at the given path
+    SecretFile(String),
+}
+
+impl std::fmt::Display for ValueSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ValueSource::Env(var) => write!(f, "from environment variable {}", var),
+            ValueSource::SecretFile(path) => write!(f, "from file {}", path),
+        }
+    }
+}
+
+/// A config value overridden by an environment variable.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EnvOverride {
+    /// Name of the environment variable
+    pub var: String,
+    /// Path of the overridden key, e.g. `["bot_filtering", "ignore_bots"]`
+    pub path: Vec<String>,
+    pub value: Value,
+    /// The value as written, used when the typed value doesn't fit the key
+    pub raw: String,
+}
+
+impl EnvOverride {
+    /// Dotted key of the overridden value, e.g. `bot_filtering.ignore_bots`.
+    pub fn key(&self) -> String {
+        self.path.join(".")
+    }
+}
+
+/// Collect config overrides from environment variables.
+///
+/// `MATRIX_BOT_HELP_ACCESS_TOKEN` overrides `access_token`, and a double
+/// underscore separates sections from keys, so
+/// `MATRIX_BOT_HELP_BOT_FILTERING__IGNORE_BOTS` overrides
+/// `ignore_bots` in `[bot_filtering]`.
+pub fn env_overrides<I>(vars: I) -> Vec<EnvOverride>
+where
+    I: IntoIterator<Item = (String, String)>,
+{
+    let mut overrides: Vec<EnvOverride> = vars
+        .into_iter()
+        .filter_map(|(var, raw)| {
+            let name = var.strip_prefix(ENV_PREFIX)?;
+            if name.is_empty() {
+                return None;
+            }
+            let path = name.to_lowercase().split("__").map(String::from).collect();
+            Some(EnvOverride {
+                path,
+                value: parse_env_value(&raw),
+                raw,
+                var,
+            })
+        })
+        .collect();
+    overrides.sort_by(|a, b| a.var.cmp(&b.var));
+    overrides
+}
+
+/// Interpret an environment variable value.
+///
+/// Booleans, numbers, arrays and quoted strings are read as TOML, so
+/// `true`, `300` and `["@a:example.com"]` keep their types. Anything else,
+/// including tokens and URLs, is taken as a plain string. When a typed value
+/// doesn't fit the key, [`apply_overrides`] falls back to the raw string.
+fn parse_env_value(raw: &str) -> Value {
+    let parsed = format!("value = {}", raw)
+        .parse::<Table>()
+        .ok()
+        .and_then(|mut table| table.remove("value"));
+    match parsed {
+        Some(
+            value @ (Value::Boolean(_)
+            | Value::Integer(_)
+            | Value::Float(_)
+            | Value::Array(_)
+            | Value::String(_)),
+        ) => value,
+        _ => Value::String(raw.to_string()),
+    }
+}
+
+/// Apply environment overrides to a parsed config table, creating sections as needed.
+///
+/// A typed value that makes the table invalid according to `accepts`, e.g. the
+/// number `123456` for a password, is applied as the raw string instead.
+pub fn apply_overrides<F>(table: &mut Table, overrides: &[EnvOverride], accepts: F) -> Result<()>
+where
+    F: Fn(&Table) -> bool,
+{
+    for o in overrides {
+        let (key, sections) = o
+            .path
+            .split_last()
+            .expect("Override path should not be empty");
+        let mut current = &mut *table;
+        for section in sections {
+            current = current
+                .entry(section.as_str())
+                .or_insert_with(|| Value::Table(Table::new()))
+                .as_table_mut()
+                .ok_or_else(|| {
+                    anyhow!(
+                        "Invalid environment variable {}: '{}' is not a section",
+                        o.var,
+                        section
+                    )
+                })?;
+        }
+        current.insert(key.clone(), o.value.clone());
+        if !o.value.is_str() && !accepts(table) {
+            let mut with_raw = table.clone();
+            set_value(&mut with_raw, &o.path, Value::String(o.raw.clone()));
+            if accepts(&with_raw) {
+                *table = with_raw;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Replace a value in a table whose sections already exist.
+fn set_value(table: &mut Table, path: &[String], value: Value) {
+    let (key, sections) = path.split_last().expect("Path should not be empty");
+    let section = sections.iter().fold(table, |current, section| {
+        current[section.as_str()]
+            .as_table_mut()
+            .expect("Section should exist")
+    });
+    section.insert(key.clone(), value);
+}
+
+/// Drop the config file's other form of each overridden secret.
+///
+/// `MATRIX_BOT_HELP_PASSWORD` replaces `password_file` from the file and the other
+/// way round, so only setting both in the same place is a conflict. `secrets` are
+/// dotted keys like `store.passphrase`.
+pub fn replace_secret_forms(table: &mut Table, overrides: &[EnvOverride], secrets: &[&str]) {
+    for secret in secrets {
+        let file_key = format!("{}_file", secret);
+        let overridden = |key: &str| overrides.iter().any(|o| o.key() == key);
+        let replaced = match (overridden(secret), overridden(&file_key)) {
+            (true, false) => file_key,
+            (false, true) => secret.to_string(),
+            _ => continue,
+        };
+        let path: Vec<&str> = replaced.split('.').collect();
+        let (key, sections) = path.split_last().expect("Secret key should not be empty");
+        let section = sections.iter().try_fold(&mut *table, |current, section| {
+            current.get_mut(*section)?.as_table_mut()
+        });
+        if let Some(section) = section {
+            section.remove(*key);
+        }
+    }
+}
+
+/// Read a secret from a file, dropping the trailing newline most editors add.
+pub fn read_secret_file(path: &str) -> Result<String> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read secret file '{}'", path))?;
+    Ok(content.trim_end_matches(['\n', '\r']).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use indoc::indoc;
+
+    fn vars(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_env_overrides_paths_and_types() {
+        // Given environment variables for top-level and section keys
+        let env = vars(&[
+            ("MATRIX_BOT_HELP_ACCESS_TOKEN", "syt_abc_123"),
+            ("MATRIX_BOT_HELP_BOT_FILTERING__IGNORE_BOTS", "true"),
+            (
+                "MATRIX_BOT_HELP_JOIN_DETECTION__WELCOME_TIMEOUT_SECONDS",
+                "600",
+            ),
+            ("MATRIX_BOT_HELP_ADMINS", "[\"@admin:example.com\"]"),
+            ("MATRIX_BOT_HELP_PASSWORD", "\"123456\""),
+            ("PATH", "/usr/bin"),
+        ]);
+
+        // When collecting overrides
+        let overrides = env_overrides(env);
+
+        // Then only prefixed variables should be used, with their types preserved
+        let found: Vec<(String, Value)> = overrides
+            .iter()
+            .map(|o| (o.key(), o.value.clone()))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                ("access_token".to_string(), Value::from("syt_abc_123")),
+                (
+                    "admins".to_string(),
+                    Value::Array(vec![Value::from("@admin:example.com")])
+                ),
+                (
+                    "bot_filtering.ignore_bots".to_string(),
+                    Value::Boolean(true)
+                ),
+                (
+                    "join_detection.welcome_timeout_seconds".to_string(),
+                    Value::Integer(600)
+                ),
+                ("password".to_string(), Value::from("123456")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_env_value_falls_back_to_string() {
+        // Given values that are not TOML literals, or are dates
+        // When interpreting them
+        // Then they should be kept as plain strings
+        assert_eq!(
+            parse_env_value("https://matrix.example.com"),
+            Value::from("https://matrix.example.com")
+        );
+        assert_eq!(parse_env_value("2024-01-01"), Value::from("2024-01-01"));
+        assert_eq!(parse_env_value(""), Value::from(""));
+    }
+
+    #[test]
+    fn test_apply_overrides_creates_sections() {
+        // Given a config table without a bot_filtering section
+        let mut table: Table = "homeserver = \"https://old.example.com\"".parse().unwrap();
+        let overrides = env_overrides(vars(&[
+            ("MATRIX_BOT_HELP_HOMESERVER", "https://new.example.com"),
+            ("MATRIX_BOT_HELP_BOT_FILTERING__IGNORE_SELF", "false"),
+        ]));
+
+        // When applying the overrides
+        apply_overrides(&mut table, &overrides, |_| true).unwrap();
+
+        // Then values should be replaced and the section created
+        assert_eq!(table["homeserver"], Value::from("https://new.example.com"));
+        assert_eq!(table["bot_filtering"]["ignore_self"], Value::Boolean(false));
+    }
+
+    #[test]
+    fn test_apply_overrides_rejects_non_section() {
+        // Given an override that treats a plain value as a section
+        let mut table: Table = "help_file = \"help.md\"".parse().unwrap();
+        let overrides = env_overrides(vars(&[("MATRIX_BOT_HELP_HELP_FILE__PATH", "x")]));
+
+        // When applying the override
+        let result = apply_overrides(&mut table, &overrides, |_| true);
+
+        // Then it should name the offending variable
+        assert!(
+            result
+                .unwrap_err()
+                .to_string()
+                .contains("MATRIX_BOT_HELP_HELP_FILE__PATH")
+        );
+    }
+
+    #[test]
+    fn test_apply_overrides_falls_back_to_raw_string() {
+        // Given unquoted numeric overrides for a string and a number key
+        let mut table = Table::new();
+        let overrides = env_overrides(vars(&[
+            ("MATRIX_BOT_HELP_PASSWORD", "123456"),
+            ("MATRIX_BOT_HELP_SYNC_RETRY__MAX_ATTEMPTS", "5"),
+        ]));
+
+        // When applying them where the password must be a string
+        apply_overrides(&mut table, &overrides, |table| {
+            table.get("password").is_none_or(Value::is_str)
+        })
+        .unwrap();
+
+        // Then only the password should fall back to the raw string
+        assert_eq!(table["password"], Value::from("123456"));
+        assert_eq!(table["sync_retry"]["max_attempts"], Value::Integer(5));
+    }
+
+    #[test]
+    fn test_replace_secret_forms() {
+        // Given secret files in the config file, and environment overrides
+        // replacing one of them and setting both forms of another
+        let mut table: Table = indoc! {"
+            access_token_file = \"token.secret\"
+            password = \"from file\"
+
+            [store]
+            passphrase_file = \"passphrase.secret\"
+        "}
+        .parse()
+        .unwrap();
+        let overrides = env_overrides(vars(&[
+            ("MATRIX_BOT_HELP_ACCESS_TOKEN", "abc"),
+            ("MATRIX_BOT_HELP_STORE__PASSPHRASE", "x"),
+            ("MATRIX_BOT_HELP_STORE__PASSPHRASE_FILE", "y"),
+        ]));
+        apply_overrides(&mut table, &overrides, |_| true).unwrap();
+
+        // When replacing the other forms of overridden secrets
+        replace_secret_forms(
+            &mut table,
+            &overrides,
+            &["access_token", "password", "store.passphrase"],
+        );
+
+        // Then the file's access token file should be gone, and the rest kept
+        assert_eq!(table["access_token"], Value::from("abc"));
+        assert!(!table.contains_key("access_token_file"));
+        assert_eq!(table["password"], Value::from("from file"));
+        assert_eq!(table["store"]["passphrase"], Value::from("x"));
+        assert_eq!(table["store"]["passphrase_file"], Value::from("y"));
+    }
+
+    #[test]
+    fn test_read_secret_file_trims_newline() {
+        // Given a secret file ending with a newline
+        let path = "test_secret_file.txt";
+        std::fs::write(path, "s3cret token\n").unwrap();
+
+        // When reading the secret
+        let secret = read_secret_file(path).unwrap();
+
+        // Then the trailing newline should be removed
+        assert_eq!(secret, "s3cret token");
+
+        // Clean up
+        std::fs::remove_file(path).unwrap();
+    }
+}
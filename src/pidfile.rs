@@ -0,0 +1,210 @@
+use anyhow::{Context, Result, anyhow};
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+/// State of the process recorded in a PID file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PidStatus {
+    /// There is no PID file.
+    NotRunning,
+    /// The PID file is left over from a process that no longer exists.
+    Stale(i32),
+    /// The process in the PID file is alive.
+    Running(i32),
+}
+
+/// Check whether a process with the given PID exists.
+pub fn is_process_alive(pid: i32) -> bool {
+    // Zero and negative PIDs would address process groups
+    if pid <= 0 {
+        return false;
+    }
+    // Signal 0 only checks whether the process exists and may be signalled
+    // SAFETY: pid is positive, so kill probes that single process and nothing else
+    if unsafe { libc::kill(pid, 0) } == 0 {
+        return true;
+    }
+    std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// Ask a process to terminate with SIGTERM.
+pub fn terminate_process(pid: i32) -> Result<()> {
+    if pid <= 0 {
+        return Err(anyhow!("Invalid PID {}", pid));
+    }
+    // SAFETY: pid is positive, so SIGTERM reaches that single process and never a process group
+    if unsafe { libc::kill(pid, libc::SIGTERM) } != 0 {
+        return Err(std::io::Error::last_os_error())
+            .with_context(|| format!("Failed to send SIGTERM to process {}", pid));
+    }
+    Ok(())
+}
+
+/// Read a PID file and check whether its process is still alive.
+pub fn pid_status(path: &Path) -> Result<PidStatus> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(PidStatus::NotRunning),
+        Err(e) => {
+            return Err(e).with_context(|| format!("Failed to read PID file '{}'", path.display()));
+        }
+    };
+
+    let pid = content
+        .trim()
+        .parse::<i32>()
+        .ok()
+        .filter(|pid| *pid > 0)
+        .ok_or_else(|| {
+            anyhow!(
+                "PID file '{}' does not contain a valid PID, remove it if no instance is running",
+                path.display()
+            )
+        })?;
+
+    if is_process_alive(pid) {
+        Ok(PidStatus::Running(pid))
+    } else {
+        Ok(PidStatus::Stale(pid))
+    }
+}
+
+/// A PID file owned by this process, removed again when dropped.
+#[derive(Debug)]
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    /// Write the current PID to `path`, refusing to if another instance is running.
+    ///
+    /// A stale PID file left behind by a crash is replaced.
+    pub fn acquire(path: &Path) -> Result<Self> {
+        match pid_status(path)? {
+            PidStatus::Running(pid) => {
+                return Err(anyhow!(
+                    "Another instance is already running with PID {} (PID file '{}')",
+                    pid,
+                    path.display()
+                ));
+            }
+            PidStatus::Stale(pid) => {
+                info!(path = %path.display(), pid, "Removing stale PID file");
+                fs::remove_file(path).with_context(|| {
+                    format!("Failed to remove stale PID file '{}'", path.display())
+                })?;
+            }
+            PidStatus::NotRunning => {}
+        }
+
+        // Only create the file if it doesn't exist, so two instances starting at
+        // the same time can't both take it
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)
+            .map_err(|e| match e.kind() {
+                ErrorKind::AlreadyExists => anyhow!(
+                    "Another instance is starting up (PID file '{}')",
+                    path.display()
+                ),
+                _ => anyhow!("Failed to create PID file '{}': {}", path.display(), e),
+            })?;
+        writeln!(file, "{}", std::process::id())
+            .with_context(|| format!("Failed to write PID file '{}'", path.display()))?;
+
+        Ok(PidFile {
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!(path = %self.path.display(), error = %e, "Failed to remove PID file");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+
+    #[test]
+    fn test_pid_status() {
+        // Given no PID file, one of a live process and one of an exited process
+        let missing = Path::new("test_pid_missing.pid");
+        let live = Path::new("test_pid_live.pid");
+        let stale = Path::new("test_pid_stale.pid");
+        let mut child = Command::new("true").spawn().unwrap();
+        let exited_pid = child.id() as i32;
+        child.wait().unwrap();
+        fs::write(live, format!("{}\n", std::process::id())).unwrap();
+        fs::write(stale, format!("{}\n", exited_pid)).unwrap();
+
+        // When checking their status
+        // Then each should be recognized
+        assert_eq!(pid_status(missing).unwrap(), PidStatus::NotRunning);
+        assert_eq!(
+            pid_status(live).unwrap(),
+            PidStatus::Running(std::process::id() as i32)
+        );
+        assert_eq!(pid_status(stale).unwrap(), PidStatus::Stale(exited_pid));
+
+        // Clean up
+        fs::remove_file(live).unwrap();
+        fs::remove_file(stale).unwrap();
+    }
+
+    #[test]
+    fn test_pid_status_rejects_invalid_content() {
+        // Given PID files with garbage and a PID that would signal a process group
+        let garbage = Path::new("test_pid_garbage.pid");
+        let group = Path::new("test_pid_group.pid");
+        fs::write(garbage, "not a pid").unwrap();
+        fs::write(group, "0").unwrap();
+
+        // When checking their status
+        // Then both should be rejected, and process groups never be signalled
+        assert!(pid_status(garbage).is_err());
+        assert!(pid_status(group).is_err());
+        assert!(!is_process_alive(0));
+        assert!(terminate_process(-1).is_err());
+
+        // Clean up
+        fs::remove_file(garbage).unwrap();
+        fs::remove_file(group).unwrap();
+    }
+
+    #[test]
+    fn test_acquire_pid_file() {
+        // Given a stale PID file
+        let path = Path::new("test_pid_acquire.pid");
+        let mut child = Command::new("true").spawn().unwrap();
+        child.wait().unwrap();
+        fs::write(path, format!("{}\n", child.id())).unwrap();
+
+        // When acquiring it twice
+        let pid_file = PidFile::acquire(path).unwrap();
+        let second = PidFile::acquire(path);
+
+        // Then the stale file should be replaced, the second attempt refused,
+        // and the file removed once released
+        assert_eq!(
+            fs::read_to_string(path).unwrap(),
+            format!("{}\n", std::process::id())
+        );
+        assert!(
+            second
+                .unwrap_err()
+                .to_string()
+                .contains("Another instance is already running")
+        );
+        drop(pid_file);
+        assert!(!path.exists());
+    }
+}
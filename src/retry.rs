@@ -0,0 +1,432 @@
+use anyhow::{Result, anyhow};
+use matrix_sdk::reqwest::StatusCode;
+use matrix_sdk::{HttpError, RumaApiError};
+use serde::{Deserialize, Deserializer};
+use std::future::Future;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// What to do once a retried operation has used up all of its attempts.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum GiveUpAction {
+    /// Log the failure and leave things as they are.
+    #[default]
+    Ignore,
+    /// Log the failure and decline the invite.
+    Decline,
+}
+
+impl FromStr for GiveUpAction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "ignore" => Ok(GiveUpAction::Ignore),
+            "decline" => Ok(GiveUpAction::Decline),
+            _ => Err(anyhow!(
+                "Invalid give up action '{}'. Valid options are: ignore, decline",
+                s
+            )),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for GiveUpAction {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        GiveUpAction::from_str(&s).map_err(serde::de::Error::custom)
+    }
+}
+
+impl std::fmt::Display for GiveUpAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GiveUpAction::Ignore => write!(f, "ignore"),
+            GiveUpAction::Decline => write!(f, "decline"),
+        }
+    }
+}
+
+/// Retry policy with exponential backoff and optional jitter.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryPolicy {
+    /// Total number of attempts, including the first one
+    pub max_attempts: u32,
+    /// Delay before the second attempt, in seconds
+    pub initial_delay_seconds: u64,
+    /// Upper bound for the delay between attempts, in seconds
+    pub max_delay_seconds: u64,
+    /// Whether to randomize delays to avoid retrying in lockstep
+    pub jitter: bool,
+    /// What to do after the last attempt fails
+    pub on_give_up: GiveUpAction,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 12,
+            initial_delay_seconds: 2,
+            max_delay_seconds: 3600,
+            jitter: true,
+            on_give_up: GiveUpAction::Ignore,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Delay to wait after the given failed attempt (1-based), before jitter.
+    ///
+    /// The delay doubles with every attempt and is capped at `max_delay_seconds`.
+    pub fn backoff_delay(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(63);
+        let seconds = self
+            .initial_delay_seconds
+            .saturating_mul(1u64 << exponent)
+            .min(self.max_delay_seconds);
+        Duration::from_secs(seconds)
+    }
+
+    /// Delay to wait after the given failed attempt (1-based), with jitter applied.
+    ///
+    /// With jitter enabled the delay is drawn uniformly from the upper half of
+    /// the backoff delay, so it never drops below half of the nominal value.
+    pub fn delay_for_attempt(&self, attempt: u32) -> Duration {
+        let delay = self.backoff_delay(attempt);
+        if !self.jitter || delay.is_zero() {
+            return delay;
+        }
+        let half = delay / 2;
+        let spread = (delay - half).as_millis() as u64;
+        half + Duration::from_millis(rand::random_range(0..=spread))
+    }
+}
+
+/// Run `operation` until it succeeds or the policy runs out of attempts.
+///
+/// `on_retry` is called after every failed attempt that will be retried, with
+/// the attempt number, the error and the delay before the next attempt. When
+/// all attempts fail, the last error is returned.
+pub async fn retry_with_policy<T, E, F, Fut, R>(
+    policy: &RetryPolicy,
+    mut operation: F,
+    mut on_retry: R,
+) -> std::result::Result<T, E>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = std::result::Result<T, E>>,
+    R: FnMut(u32, &E, Duration),
+{
+    let mut attempt = 1;
+    loop {
+        match operation().await {
+            Ok(value) => return Ok(value),
+            Err(e) if attempt >= policy.max_attempts => return Err(e),
+            Err(e) => {
+                let delay = policy.delay_for_attempt(attempt);
+                on_retry(attempt, &e, delay);
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+        }
+    }
+}
+
+/// Backoff for reconnecting the sync loop, which never gives up on retryable errors.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReconnectPolicy {
+    /// Delay after the first failure, in seconds
+    pub initial_delay_seconds: u64,
+    /// Upper bound for the delay between reconnects, in seconds
+    pub max_delay_seconds: u64,
+    /// Whether to randomize delays to avoid reconnecting in lockstep
+    pub jitter: bool,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            initial_delay_seconds: 1,
+            max_delay_seconds: 300,
+            jitter: true,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// Delay to wait after the given number of consecutive failures.
+    pub fn delay_for_failure(&self, failures: u32) -> Duration {
+        RetryPolicy {
+            initial_delay_seconds: self.initial_delay_seconds,
+            max_delay_seconds: self.max_delay_seconds,
+            jitter: self.jitter,
+            ..RetryPolicy::default()
+        }
+        .delay_for_attempt(failures)
+    }
+}
+
+/// Run `step` over and over until it fails with an error that isn't retryable.
+///
+/// After a retryable error the next step is delayed with exponential backoff,
+/// and `on_error` is called with the number of consecutive failures, the error
+/// and the delay. A successful step resets the backoff, calling `on_recover`
+/// with the number of failures it recovered from. The fatal error is returned.
+pub async fn run_with_reconnect<E, F, Fut, P, R, C>(
+    policy: &ReconnectPolicy,
+    mut step: F,
+    is_retryable: P,
+    mut on_error: R,
+    mut on_recover: C,
+) -> E
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = std::result::Result<(), E>>,
+    P: Fn(&E) -> bool,
+    R: FnMut(u32, &E, Duration),
+    C: FnMut(u32),
+{
+    let mut failures = 0;
+    loop {
+        match step().await {
+            Ok(()) => {
+                if failures > 0 {
+                    on_recover(failures);
+                    failures = 0;
+                }
+            }
+            Err(e) if !is_retryable(&e) => return e,
+            Err(e) => {
+                failures += 1;
+                let delay = policy.delay_for_failure(failures);
+                on_error(failures, &e, delay);
+                tokio::time::sleep(delay).await;
+            }
+        }
+    }
+}
+
+/// Check whether a failed sync is worth retrying.
+///
+/// Network errors, rate limiting and server errors (including a 502 from a
+/// reverse proxy while the homeserver restarts) are transient. Everything
+/// else, in particular rejected credentials, is treated as fatal.
+pub fn is_retryable_sync_error(error: &matrix_sdk::Error) -> bool {
+    let matrix_sdk::Error::Http(http_error) = error else {
+        return false;
+    };
+    let status_code = match http_error.as_ref() {
+        HttpError::Reqwest(_) => return true,
+        _ => match http_error.as_ruma_api_error() {
+            Some(RumaApiError::ClientApi(e)) => e.status_code,
+            Some(RumaApiError::Other(e)) => e.status_code,
+            Some(RumaApiError::Uiaa(_)) | None => return false,
+        },
+    };
+    status_code == StatusCode::TOO_MANY_REQUESTS || status_code.is_server_error()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use matrix_sdk::ruma::api::client::error::{Error as ClientApiError, ErrorBody, ErrorKind};
+    use matrix_sdk::ruma::api::error::FromHttpResponseError;
+    use std::cell::{Cell, RefCell};
+    use std::collections::VecDeque;
+
+    fn policy(max_attempts: u32, jitter: bool) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            initial_delay_seconds: 2,
+            max_delay_seconds: 60,
+            jitter,
+            on_give_up: GiveUpAction::Ignore,
+        }
+    }
+
+    #[test]
+    fn test_backoff_delay_doubles_and_caps() {
+        // Given a policy starting at 2 seconds and capped at 60 seconds
+        let policy = policy(10, false);
+
+        // When computing delays for successive attempts
+        let delays: Vec<u64> = (1..=7)
+            .map(|attempt| policy.backoff_delay(attempt).as_secs())
+            .collect();
+
+        // Then delays should double until they reach the cap
+        assert_eq!(delays, vec![2, 4, 8, 16, 32, 60, 60]);
+        assert_eq!(policy.backoff_delay(u32::MAX).as_secs(), 60);
+    }
+
+    #[test]
+    fn test_delay_with_jitter_stays_in_bounds() {
+        // Given a policy with jitter enabled
+        let policy = policy(10, true);
+
+        // When computing jittered delays many times
+        for attempt in 1..=6 {
+            let nominal = policy.backoff_delay(attempt);
+            for _ in 0..50 {
+                let delay = policy.delay_for_attempt(attempt);
+
+                // Then each delay should be between half and the full nominal delay
+                assert!(delay >= nominal / 2);
+                assert!(delay <= nominal);
+            }
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_retry_stops_after_first_success() {
+        // Given a join function that fails twice and then succeeds
+        let calls = Cell::new(0);
+        let join = || {
+            calls.set(calls.get() + 1);
+            let n = calls.get();
+            async move { if n < 3 { Err("busy") } else { Ok(n) } }
+        };
+        let mut retries = Vec::new();
+
+        // When retrying with a generous policy
+        let result = retry_with_policy(&policy(5, false), join, |attempt, _, delay| {
+            retries.push((attempt, delay.as_secs()))
+        })
+        .await;
+
+        // Then it should succeed on the third call and never call join again
+        assert_eq!(result, Ok(3));
+        assert_eq!(calls.get(), 3);
+        assert_eq!(retries, vec![(1, 2), (2, 4)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_retry_gives_up_after_max_attempts() {
+        // Given a join function that always fails
+        let calls = Cell::new(0);
+        let join = || {
+            calls.set(calls.get() + 1);
+            async { Err::<(), _>("forbidden") }
+        };
+
+        // When retrying with a policy allowing three attempts
+        let result = retry_with_policy(&policy(3, true), join, |_, _, _| {}).await;
+
+        // Then it should give up with the last error after exactly three calls
+        assert_eq!(result, Err("forbidden"));
+        assert_eq!(calls.get(), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_retry_single_attempt_does_not_retry() {
+        // Given a policy that allows only one attempt
+        let calls = Cell::new(0);
+        let join = || {
+            calls.set(calls.get() + 1);
+            async { Err::<(), _>("forbidden") }
+        };
+        let mut retried = false;
+
+        // When the only attempt fails
+        let result = retry_with_policy(&policy(1, false), join, |_, _, _| retried = true).await;
+
+        // Then no retry should be scheduled
+        assert!(result.is_err());
+        assert_eq!(calls.get(), 1);
+        assert!(!retried);
+    }
+
+    #[test]
+    fn test_give_up_action_parsing() {
+        // Given valid and invalid give up action names
+        // When parsing them
+        // Then valid names should parse and invalid ones should error
+        assert_eq!(
+            GiveUpAction::from_str("ignore").unwrap(),
+            GiveUpAction::Ignore
+        );
+        assert_eq!(
+            GiveUpAction::from_str("Decline").unwrap(),
+            GiveUpAction::Decline
+        );
+        assert!(
+            GiveUpAction::from_str("explode")
+                .unwrap_err()
+                .to_string()
+                .contains("Invalid give up action")
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_run_with_reconnect_backs_off_and_resets() {
+        // Given a step that fails twice, recovers, fails again and then fails fatally
+        let policy = ReconnectPolicy {
+            initial_delay_seconds: 1,
+            max_delay_seconds: 300,
+            jitter: false,
+        };
+        let results = RefCell::new(VecDeque::from([
+            Err("network"),
+            Err("network"),
+            Ok(()),
+            Err("network"),
+            Err("fatal"),
+        ]));
+        let mut failures = Vec::new();
+        let mut recovered = Vec::new();
+        let start = tokio::time::Instant::now();
+
+        // When running it with reconnects
+        let error = run_with_reconnect(
+            &policy,
+            || {
+                let result = results.borrow_mut().pop_front().unwrap();
+                async move { result }
+            },
+            |e: &&str| *e == "network",
+            |failure, _, delay| failures.push((failure, delay.as_secs())),
+            |count| recovered.push(count),
+        )
+        .await;
+
+        // Then it should back off, reset after the success and stop at the fatal error
+        assert_eq!(error, "fatal");
+        assert_eq!(failures, vec![(1, 1), (2, 2), (1, 1)]);
+        assert_eq!(recovered, vec![2]);
+        assert_eq!(start.elapsed(), Duration::from_secs(4));
+    }
+
+    fn api_error(status: u16, kind: ErrorKind) -> matrix_sdk::Error {
+        let error = ClientApiError::new(
+            StatusCode::from_u16(status).unwrap(),
+            ErrorBody::Standard {
+                kind,
+                message: "error".to_string(),
+            },
+        );
+        let http_error = HttpError::Api(Box::new(FromHttpResponseError::Server(
+            RumaApiError::ClientApi(error),
+        )));
+        matrix_sdk::Error::Http(Box::new(http_error))
+    }
+
+    #[test]
+    fn test_is_retryable_sync_error() {
+        // Given server, rate limit, auth and client errors
+        let bad_gateway = api_error(502, ErrorKind::Unknown);
+        let rate_limited = api_error(429, ErrorKind::LimitExceeded { retry_after: None });
+        let unknown_token = api_error(401, ErrorKind::UnknownToken { soft_logout: false });
+        let forbidden = api_error(403, ErrorKind::forbidden());
+        let not_logged_in = matrix_sdk::Error::AuthenticationRequired;
+
+        // When classifying them
+        // Then only transient errors should be retried
+        assert!(is_retryable_sync_error(&bad_gateway));
+        assert!(is_retryable_sync_error(&rate_limited));
+        assert!(!is_retryable_sync_error(&unknown_token));
+        assert!(!is_retryable_sync_error(&forbidden));
+        assert!(!is_retryable_sync_error(&not_logged_in));
+    }
+}
@@ -0,0 +1,218 @@
+use anyhow::{Context, Result};
+use matrix_sdk::authentication::matrix::MatrixSession;
+use matrix_sdk::ruma::api::client::error::ErrorKind;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+/// Process exit code used when the bot can no longer authenticate.
+///
+/// Supervisors can watch for this code to alert on expired or revoked credentials
+/// instead of restarting the bot in a loop.
+pub const EXIT_AUTH_FAILURE: i32 = 3;
+
+/// Error returned when the homeserver rejects the bot's credentials.
+#[derive(Debug)]
+pub struct AuthenticationError {
+    /// Whether the bot had logged in successfully before the credentials were rejected
+    pub after_login: bool,
+    pub message: String,
+}
+
+impl std::fmt::Display for AuthenticationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Authentication failed: {}", self.message)
+    }
+}
+
+impl std::error::Error for AuthenticationError {}
+
+/// Check whether an SDK error means the access token is no longer valid.
+pub fn is_unknown_token(error: &matrix_sdk::Error) -> bool {
+    matches!(
+        error.client_api_error_kind(),
+        Some(ErrorKind::UnknownToken { .. })
+    )
+}
+
+/// Check whether an SDK error means the homeserver rejected the bot's credentials,
+/// rather than failing for another reason such as being unreachable.
+pub fn is_rejected_credentials(error: &matrix_sdk::Error) -> bool {
+    matches!(
+        error.client_api_error_kind(),
+        Some(
+            ErrorKind::Forbidden { .. }
+                | ErrorKind::UserDeactivated
+                | ErrorKind::UnknownToken { .. }
+        )
+    )
+}
+
+/// Check whether a configured access token should replace the one in a saved session.
+///
+/// Refreshable tokens are expected to differ once the homeserver rotated them, so only
+/// a different token without refresh tokens is taken to have been changed on purpose.
+pub fn configured_token_replaces(
+    session: &MatrixSession,
+    access_token: &str,
+    refresh_token: Option<&str>,
+) -> bool {
+    session.tokens.access_token != access_token
+        && refresh_token.is_none()
+        && session.tokens.refresh_token.is_none()
+}
+
+/// Load a persisted Matrix session from a JSON file.
+pub fn load_session(path: &Path) -> Result<MatrixSession> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read session file '{}'", path.display()))?;
+    serde_json::from_str(&content)
+        .with_context(|| format!("Failed to parse session file '{}'", path.display()))
+}
+
+/// Persist a Matrix session to a JSON file that only the owner can read.
+///
+/// The session is written to a temporary file first and then renamed, so a
+/// crash never leaves a truncated session behind.
+pub fn save_session(path: &Path, session: &MatrixSession) -> Result<()> {
+    let content = serde_json::to_string_pretty(session).context("Failed to serialize session")?;
+    let tmp_path = path.with_extension("tmp");
+
+    let mut file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp_path)
+        .with_context(|| format!("Failed to open session file '{}'", tmp_path.display()))?;
+    file.write_all(content.as_bytes())
+        .and_then(|_| file.sync_all())
+        .with_context(|| format!("Failed to write session file '{}'", tmp_path.display()))?;
+
+    fs::rename(&tmp_path, path)
+        .with_context(|| format!("Failed to save session file '{}'", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use matrix_sdk::reqwest::StatusCode;
+    use matrix_sdk::ruma::api::client::error::{Error as ClientApiError, ErrorBody};
+    use matrix_sdk::ruma::api::error::FromHttpResponseError;
+    use matrix_sdk::{HttpError, RumaApiError};
+    use matrix_sdk::{SessionMeta, SessionTokens, ruma::owned_device_id, ruma::owned_user_id};
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn test_save_and_load_session_round_trip() {
+        // Given a session with a refresh token
+        let session = MatrixSession {
+            meta: SessionMeta {
+                user_id: owned_user_id!("@bot:example.com"),
+                device_id: owned_device_id!("ABCDEFGH"),
+            },
+            tokens: SessionTokens {
+                access_token: "access".to_string(),
+                refresh_token: Some("refresh".to_string()),
+            },
+        };
+        let path = Path::new("test_session_round_trip.json");
+
+        // When saving and loading it again
+        save_session(path, &session).unwrap();
+        let loaded = load_session(path).unwrap();
+        let mode = fs::metadata(path).unwrap().permissions().mode();
+
+        // Then the loaded session should match and only the owner may read it
+        assert_eq!(loaded, session);
+        assert_eq!(mode & 0o777, 0o600);
+
+        // Clean up
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_load_session_invalid_content() {
+        // Given a session file with invalid content
+        let path = Path::new("test_session_invalid.json");
+        fs::write(path, "not json").unwrap();
+
+        // When loading the session
+        let result = load_session(path);
+
+        // Then it should return a parse error
+        assert!(
+            result
+                .unwrap_err()
+                .to_string()
+                .contains("Failed to parse session file")
+        );
+
+        // Clean up
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_configured_token_replaces() {
+        // Given saved sessions with and without a refresh token
+        let session = |refresh_token: Option<&str>| MatrixSession {
+            meta: SessionMeta {
+                user_id: owned_user_id!("@bot:example.com"),
+                device_id: owned_device_id!("ABCDEFGH"),
+            },
+            tokens: SessionTokens {
+                access_token: "saved".to_string(),
+                refresh_token: refresh_token.map(str::to_string),
+            },
+        };
+        let plain = session(None);
+        let refreshable = session(Some("refresh"));
+
+        // When comparing them with configured tokens
+        // Then only a changed token that can't have been rotated should replace it
+        assert!(configured_token_replaces(&plain, "configured", None));
+        assert!(!configured_token_replaces(&plain, "saved", None));
+        assert!(!configured_token_replaces(
+            &plain,
+            "configured",
+            Some("refresh")
+        ));
+        assert!(!configured_token_replaces(&refreshable, "configured", None));
+    }
+
+    fn api_error(status: u16, kind: ErrorKind) -> matrix_sdk::Error {
+        let error = ClientApiError::new(
+            StatusCode::from_u16(status).unwrap(),
+            ErrorBody::Standard {
+                kind,
+                message: "error".to_string(),
+            },
+        );
+        let http_error = HttpError::Api(Box::new(FromHttpResponseError::Server(
+            RumaApiError::ClientApi(error),
+        )));
+        matrix_sdk::Error::Http(Box::new(http_error))
+    }
+
+    #[test]
+    fn test_is_rejected_credentials() {
+        // Given login failures for bad credentials, a deactivated account,
+        // a revoked token, rate limiting, a server error and no connection
+        let forbidden = api_error(403, ErrorKind::forbidden());
+        let deactivated = api_error(403, ErrorKind::UserDeactivated);
+        let unknown_token = api_error(401, ErrorKind::UnknownToken { soft_logout: false });
+        let rate_limited = api_error(429, ErrorKind::LimitExceeded { retry_after: None });
+        let bad_gateway = api_error(502, ErrorKind::Unknown);
+        let not_logged_in = matrix_sdk::Error::AuthenticationRequired;
+
+        // When classifying them
+        // Then only the rejected credentials should count as authentication failures
+        assert!(is_rejected_credentials(&forbidden));
+        assert!(is_rejected_credentials(&deactivated));
+        assert!(is_rejected_credentials(&unknown_token));
+        assert!(!is_rejected_credentials(&rate_limited));
+        assert!(!is_rejected_credentials(&bad_gateway));
+        assert!(!is_rejected_credentials(&not_logged_in));
+    }
+}
@@ -0,0 +1,134 @@
+use std::sync::Arc;
+use tokio::sync::watch;
+
+#[derive(Debug, Clone, Copy, Default)]
+struct State {
+    shutting_down: bool,
+    in_flight: usize,
+}
+
+/// Coordinates a graceful shutdown between the sync loop and event handlers.
+///
+/// Handlers register the work they do with [`Shutdown::start_operation`]. Once
+/// a shutdown has been triggered no new operations are started, and the bot
+/// can wait for the running ones to finish before exiting.
+#[derive(Debug, Clone)]
+pub struct Shutdown {
+    state: Arc<watch::Sender<State>>,
+}
+
+/// Marks an operation as in flight until it is dropped.
+#[derive(Debug)]
+pub struct OperationGuard {
+    state: Arc<watch::Sender<State>>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self {
+            state: Arc::new(watch::Sender::new(State::default())),
+        }
+    }
+
+    /// Start an operation that should finish before the bot exits.
+    ///
+    /// Returns `None` once a shutdown has been triggered, in which case the
+    /// operation should not be started at all.
+    pub fn start_operation(&self) -> Option<OperationGuard> {
+        let mut started = false;
+        self.state.send_if_modified(|state| {
+            if state.shutting_down {
+                return false;
+            }
+            state.in_flight += 1;
+            started = true;
+            true
+        });
+        started.then(|| OperationGuard {
+            state: self.state.clone(),
+        })
+    }
+
+    /// Stop accepting new operations.
+    pub fn trigger(&self) {
+        self.state.send_modify(|state| state.shutting_down = true);
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.state.borrow().shutting_down
+    }
+
+    /// Number of operations that are still running.
+    pub fn in_flight(&self) -> usize {
+        self.state.borrow().in_flight
+    }
+
+    /// Wait until a shutdown has been triggered.
+    pub async fn triggered(&self) {
+        let mut receiver = self.state.subscribe();
+        // The sender lives in `self`, so waiting can't fail
+        let _ = receiver.wait_for(|state| state.shutting_down).await;
+    }
+
+    /// Wait until no operations are running.
+    pub async fn idle(&self) {
+        let mut receiver = self.state.subscribe();
+        let _ = receiver.wait_for(|state| state.in_flight == 0).await;
+    }
+}
+
+impl Drop for OperationGuard {
+    fn drop(&mut self) {
+        self.state.send_modify(|state| state.in_flight -= 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_operations_are_refused_after_trigger() {
+        // Given a running operation
+        let shutdown = Shutdown::new();
+        let guard = shutdown.start_operation();
+
+        // When triggering a shutdown
+        shutdown.trigger();
+
+        // Then the running operation should be counted and new ones refused
+        assert!(guard.is_some());
+        assert!(shutdown.is_triggered());
+        assert_eq!(shutdown.in_flight(), 1);
+        assert!(shutdown.start_operation().is_none());
+        drop(guard);
+        assert_eq!(shutdown.in_flight(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_idle_waits_for_running_operations() {
+        // Given an operation that takes five seconds
+        let shutdown = Shutdown::new();
+        let guard = shutdown.start_operation().unwrap();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            drop(guard);
+        });
+
+        // When shutting down and waiting with a short and a long timeout
+        shutdown.trigger();
+        let short = tokio::time::timeout(Duration::from_secs(1), shutdown.idle()).await;
+        let long = tokio::time::timeout(Duration::from_secs(10), shutdown.idle()).await;
+
+        // Then only the long timeout should see the operation finish
+        assert!(short.is_err());
+        assert!(long.is_ok());
+    }
+}
@@ -0,0 +1,577 @@
+use anyhow::{Context, Result, anyhow};
+use serde::{Deserialize, Deserializer, Serialize};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// How much usage statistics keep about who asked for help.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum StatsPrivacy {
+    /// Keep the user IDs of requesters, to count unique requesters across restarts
+    #[default]
+    Detailed,
+    /// Keep only counts and salted hashes of requesters, never user IDs.
+    /// The salt is kept in a separate owner-only file.
+    Aggregated,
+}
+
+impl FromStr for StatsPrivacy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "detailed" => Ok(StatsPrivacy::Detailed),
+            "aggregated" => Ok(StatsPrivacy::Aggregated),
+            _ => Err(anyhow!(
+                "Invalid stats privacy '{}'. Valid options are: detailed, aggregated",
+                s
+            )),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for StatsPrivacy {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        StatsPrivacy::from_str(&s).map_err(serde::de::Error::custom)
+    }
+}
+
+impl fmt::Display for StatsPrivacy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StatsPrivacy::Detailed => write!(f, "detailed"),
+            StatsPrivacy::Aggregated => write!(f, "aggregated"),
+        }
+    }
+}
+
+/// Configuration for usage statistics.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StatsConfig {
+    /// Whether to record usage statistics at all
+    pub enabled: bool,
+    /// JSON file the statistics are kept in, relative to the working directory
+    pub file: String,
+    /// Whether to keep requesters' user IDs
+    pub privacy: StatsPrivacy,
+}
+
+impl Default for StatsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            file: "stats.json".to_string(),
+            privacy: StatsPrivacy::Detailed,
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct RoomUsage {
+    /// Help requests answered, by topic
+    #[serde(default)]
+    help_requests: BTreeMap<String, u64>,
+    #[serde(default)]
+    welcomes: u64,
+    #[serde(default)]
+    unique_requesters: u64,
+    /// Only kept with detailed privacy
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    requesters: BTreeSet<String>,
+    /// Salted hashes of requesters, kept instead of their user IDs with aggregated privacy
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    requester_hashes: BTreeSet<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct StatsFile {
+    /// Seconds since the Unix epoch when recording started
+    since: u64,
+    #[serde(default)]
+    rooms: BTreeMap<String, RoomUsage>,
+}
+
+/// Read the statistics file, which may not exist yet.
+fn read_stats_file(path: &Path) -> Result<Option<StatsFile>> {
+    match fs::read_to_string(path) {
+        Ok(content) => serde_json::from_str(&content)
+            .map(Some)
+            .with_context(|| format!("Failed to parse stats file '{}'", path.display())),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("Failed to read stats file '{}'", path.display())),
+    }
+}
+
+/// Path of the file with the salt for requester hashes, next to the statistics file.
+pub fn salt_path(stats_path: &Path) -> PathBuf {
+    stats_path.with_extension("salt")
+}
+
+/// Read the salt for requester hashes, creating a random one that only the owner can read.
+///
+/// It is kept out of the statistics file, so that the file alone can't be used to check
+/// whether a known user asked for help.
+fn load_salt(path: &Path) -> Result<String> {
+    match fs::read_to_string(path) {
+        Ok(salt) => return Ok(salt.trim_end().to_string()),
+        Err(e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => {
+            return Err(e)
+                .with_context(|| format!("Failed to read salt file '{}'", path.display()));
+        }
+    }
+    let salt: [u8; 16] = rand::random();
+    let salt: String = salt.iter().map(|byte| format!("{:02x}", byte)).collect();
+    OpenOptions::new()
+        .create_new(true)
+        .write(true)
+        .mode(0o600)
+        .open(path)
+        .and_then(|mut file| writeln!(file, "{}", salt))
+        .with_context(|| format!("Failed to write salt file '{}'", path.display()))?;
+    Ok(salt)
+}
+
+/// Hash a requester with the salt and the room, so hashes can't be matched across
+/// rooms, or against a list of known user IDs by anyone without the salt file.
+fn requester_hash(salt: &str, room_id: &str, user_id: &str) -> String {
+    let digest = Sha256::new()
+        .chain_update(salt)
+        .chain_update([0])
+        .chain_update(room_id)
+        .chain_update([0])
+        .chain_update(user_id)
+        .finalize();
+    digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn now_seconds() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs())
+        .unwrap_or_default()
+}
+
+/// Help requests and welcomes per room, kept in a JSON file across restarts.
+#[derive(Debug)]
+pub struct UsageStats {
+    path: PathBuf,
+    privacy: StatsPrivacy,
+    /// Salt for requester hashes, only loaded with aggregated privacy
+    salt: String,
+    data: StatsFile,
+}
+
+impl UsageStats {
+    /// Load the statistics from `path`, which may not exist yet.
+    ///
+    /// With aggregated privacy, user IDs recorded earlier are replaced by their hashes,
+    /// salted with the secret kept in [`salt_path`].
+    pub fn load(path: &Path, privacy: &StatsPrivacy) -> Result<Self> {
+        let data = read_stats_file(path)?.unwrap_or_else(|| StatsFile {
+            since: now_seconds(),
+            rooms: BTreeMap::new(),
+        });
+        let salt = match privacy {
+            StatsPrivacy::Detailed => String::new(),
+            StatsPrivacy::Aggregated => load_salt(&salt_path(path))?,
+        };
+        let mut stats = Self {
+            path: path.to_path_buf(),
+            privacy: privacy.clone(),
+            salt,
+            data,
+        };
+        if stats.privacy == StatsPrivacy::Aggregated {
+            for (room_id, room) in stats.data.rooms.iter_mut() {
+                let hashes = room
+                    .requesters
+                    .iter()
+                    .map(|user_id| requester_hash(&stats.salt, room_id, user_id));
+                room.requester_hashes.extend(hashes);
+                room.requesters.clear();
+            }
+            stats.save()?;
+        }
+        Ok(stats)
+    }
+
+    /// Count a help request answered in a room.
+    pub fn help_request(&mut self, room_id: &str, topic: &str, user_id: &str) -> Result<()> {
+        let room = self.data.rooms.entry(room_id.to_string()).or_default();
+        *room.help_requests.entry(topic.to_string()).or_default() += 1;
+        let new_requester = match self.privacy {
+            StatsPrivacy::Detailed => room.requesters.insert(user_id.to_string()),
+            StatsPrivacy::Aggregated => room
+                .requester_hashes
+                .insert(requester_hash(&self.salt, room_id, user_id)),
+        };
+        if new_requester {
+            room.unique_requesters += 1;
+        }
+        self.save()
+    }
+
+    /// Count a welcome sent in a room.
+    pub fn welcome(&mut self, room_id: &str) -> Result<()> {
+        self.data
+            .rooms
+            .entry(room_id.to_string())
+            .or_default()
+            .welcomes += 1;
+        self.save()
+    }
+
+    pub fn report(&self) -> StatsReport {
+        StatsReport::new(&self.data)
+    }
+
+    /// Write the statistics to a temporary file and rename it, so they are never left half written.
+    fn save(&self) -> Result<()> {
+        let content =
+            serde_json::to_string_pretty(&self.data).context("Failed to serialize stats")?;
+        let tmp_path = self.path.with_extension("tmp");
+        fs::write(&tmp_path, content)
+            .with_context(|| format!("Failed to write stats file '{}'", tmp_path.display()))?;
+        fs::rename(&tmp_path, &self.path)
+            .with_context(|| format!("Failed to save stats file '{}'", self.path.display()))
+    }
+}
+
+/// Usage of one room, without any user IDs.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RoomReport {
+    pub room_id: String,
+    pub help_requests: u64,
+    /// Help requests by topic
+    pub topics: BTreeMap<String, u64>,
+    pub welcomes: u64,
+    pub unique_requesters: u64,
+}
+
+/// Usage statistics as reported by `!bot stats` and the `stats` subcommand.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StatsReport {
+    /// Seconds since the Unix epoch when recording started
+    pub since: u64,
+    /// Rooms with the most help requests first
+    pub rooms: Vec<RoomReport>,
+}
+
+impl StatsReport {
+    fn new(data: &StatsFile) -> Self {
+        let mut rooms: Vec<RoomReport> = data
+            .rooms
+            .iter()
+            .map(|(room_id, usage)| RoomReport {
+                room_id: room_id.clone(),
+                help_requests: usage.help_requests.values().sum(),
+                topics: usage.help_requests.clone(),
+                welcomes: usage.welcomes,
+                unique_requesters: usage.unique_requesters,
+            })
+            .collect();
+        rooms.sort_by(|a, b| {
+            (b.help_requests, b.welcomes)
+                .cmp(&(a.help_requests, a.welcomes))
+                .then_with(|| a.room_id.cmp(&b.room_id))
+        });
+        Self {
+            since: data.since,
+            rooms,
+        }
+    }
+
+    /// Read the report from the statistics file of a bot.
+    pub fn read(path: &Path) -> Result<Self> {
+        match read_stats_file(path)? {
+            Some(data) => Ok(Self::new(&data)),
+            None => Err(anyhow!(
+                "No usage statistics at '{}'. Are they enabled with [stats] enabled = true?",
+                path.display()
+            )),
+        }
+    }
+
+    /// A readable summary listing at most `max_rooms` rooms, named by `room_name` where known.
+    pub fn to_text(&self, max_rooms: usize, room_name: impl Fn(&str) -> Option<String>) -> String {
+        let help_requests: u64 = self.rooms.iter().map(|room| room.help_requests).sum();
+        let welcomes: u64 = self.rooms.iter().map(|room| room.welcomes).sum();
+        let mut text = format!(
+            "Usage since {}: {} help requests and {} welcomes in {} rooms",
+            format_date(self.since),
+            help_requests,
+            welcomes,
+            self.rooms.len()
+        );
+        for room in self.rooms.iter().take(max_rooms) {
+            let label = match room_name(&room.room_id) {
+                Some(name) => format!("{} ({})", name, room.room_id),
+                None => room.room_id.clone(),
+            };
+            let topics: Vec<String> = room
+                .topics
+                .iter()
+                .map(|(topic, count)| format!("{} {}", topic, count))
+                .collect();
+            text.push_str(&format!(
+                "\n- {}: {} help requests{}, {} unique requesters, {} welcomes",
+                label,
+                room.help_requests,
+                if topics.is_empty() {
+                    String::new()
+                } else {
+                    format!(" ({})", topics.join(", "))
+                },
+                room.unique_requesters,
+                room.welcomes
+            ));
+        }
+        if self.rooms.len() > max_rooms {
+            text.push_str(&format!(
+                "\n... and {} more rooms",
+                self.rooms.len() - max_rooms
+            ));
+        }
+        text
+    }
+
+    /// One line per room, with a `topic:<name>` column for each topic.
+    pub fn to_csv(&self) -> String {
+        let topics: BTreeSet<&String> = self
+            .rooms
+            .iter()
+            .flat_map(|room| room.topics.keys())
+            .collect();
+        let mut header = vec![
+            "room_id".to_string(),
+            "help_requests".to_string(),
+            "welcomes".to_string(),
+            "unique_requesters".to_string(),
+        ];
+        header.extend(
+            topics
+                .iter()
+                .map(|topic| csv_field(&format!("topic:{}", topic)).into_owned()),
+        );
+        let mut csv = header.join(",") + "\n";
+        for room in &self.rooms {
+            let mut row = vec![
+                csv_field(&room.room_id).into_owned(),
+                room.help_requests.to_string(),
+                room.welcomes.to_string(),
+                room.unique_requesters.to_string(),
+            ];
+            row.extend(
+                topics
+                    .iter()
+                    .map(|topic| room.topics.get(*topic).copied().unwrap_or(0).to_string()),
+            );
+            csv.push_str(&row.join(","));
+            csv.push('\n');
+        }
+        csv
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        serde_json::to_string_pretty(self).context("Failed to serialize stats")
+    }
+}
+
+/// Quote a CSV field if it contains a separator, quote or line break.
+fn csv_field(value: &str) -> Cow<'_, str> {
+    if value.contains([',', '"', '\n', '\r']) {
+        Cow::Owned(format!("\"{}\"", value.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(value)
+    }
+}
+
+/// Format seconds since the Unix epoch as a UTC date like `2024-03-01`.
+fn format_date(seconds: u64) -> String {
+    // Converts days to a civil date, counting from 0000-03-01 so leap days end each year
+    let days = (seconds / 86400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn test_usage_stats_are_persisted() {
+        // Given empty statistics in a file that doesn't exist yet
+        let path = Path::new("test_usage_stats.json");
+        let _ = fs::remove_file(path);
+        let mut stats = UsageStats::load(path, &StatsPrivacy::Detailed).unwrap();
+
+        // When recording help requests by two users, one twice, and a welcome
+        stats
+            .help_request("!a:example.com", "help", "@alice:example.com")
+            .unwrap();
+        stats
+            .help_request("!a:example.com", "help", "@alice:example.com")
+            .unwrap();
+        stats
+            .help_request("!a:example.com", "rules", "@bob:example.com")
+            .unwrap();
+        stats
+            .help_request("!b:example.com", "help", "@alice:example.com")
+            .unwrap();
+        stats.welcome("!c:example.com").unwrap();
+
+        // Then reading the file again should count them per room and topic
+        let report = StatsReport::read(path).unwrap();
+        assert_eq!(report.since, stats.report().since);
+        assert_eq!(
+            report.rooms[0],
+            RoomReport {
+                room_id: "!a:example.com".to_string(),
+                help_requests: 3,
+                topics: BTreeMap::from([("help".to_string(), 2), ("rules".to_string(), 1)]),
+                welcomes: 0,
+                unique_requesters: 2,
+            }
+        );
+        assert_eq!(report.rooms[1].room_id, "!b:example.com");
+        assert_eq!(report.rooms[1].unique_requesters, 1);
+        assert_eq!(report.rooms[2].room_id, "!c:example.com");
+        assert_eq!(report.rooms[2].welcomes, 1);
+        assert!(
+            fs::read_to_string(path)
+                .unwrap()
+                .contains("@alice:example.com")
+        );
+
+        // Clean up
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_aggregated_stats_keep_no_user_ids() {
+        // Given detailed statistics with a requester
+        let path = Path::new("test_aggregated_stats.json");
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_file(salt_path(path));
+        let mut detailed = UsageStats::load(path, &StatsPrivacy::Detailed).unwrap();
+        detailed
+            .help_request("!a:example.com", "help", "@alice:example.com")
+            .unwrap();
+
+        // When loading them with aggregated privacy and recording more requests,
+        // including from the same users after loading them again
+        let mut aggregated = UsageStats::load(path, &StatsPrivacy::Aggregated).unwrap();
+        let content_after_load = fs::read_to_string(path).unwrap();
+        aggregated
+            .help_request("!a:example.com", "help", "@bob:example.com")
+            .unwrap();
+        let mut restarted = UsageStats::load(path, &StatsPrivacy::Aggregated).unwrap();
+        restarted
+            .help_request("!a:example.com", "help", "@bob:example.com")
+            .unwrap();
+        restarted
+            .help_request("!a:example.com", "help", "@alice:example.com")
+            .unwrap();
+
+        // Then user IDs should be removed from the file, while still counting unique
+        // requesters across restarts, with a salt that is kept apart and private
+        let content = fs::read_to_string(path).unwrap();
+        assert!(!content_after_load.contains("@alice"));
+        assert!(!content.contains("@alice") && !content.contains("@bob"));
+        let room = &StatsReport::read(path).unwrap().rooms[0];
+        assert_eq!(room.help_requests, 4);
+        assert_eq!(room.unique_requesters, 2);
+        let salt = fs::read_to_string(salt_path(path)).unwrap();
+        let mode = fs::metadata(salt_path(path)).unwrap().permissions().mode();
+        assert!(!content.contains(salt.trim_end()));
+        assert_eq!(mode & 0o777, 0o600);
+
+        // Clean up
+        fs::remove_file(path).unwrap();
+        fs::remove_file(salt_path(path)).unwrap();
+    }
+
+    #[test]
+    fn test_stats_report_formats() {
+        // Given a report of two rooms
+        let report = StatsReport {
+            since: 1_709_251_200,
+            rooms: vec![
+                RoomReport {
+                    room_id: "!a:example.com".to_string(),
+                    help_requests: 3,
+                    topics: BTreeMap::from([("help".to_string(), 2), ("rules".to_string(), 1)]),
+                    welcomes: 1,
+                    unique_requesters: 2,
+                },
+                RoomReport {
+                    room_id: "!b,c:example.com".to_string(),
+                    help_requests: 0,
+                    topics: BTreeMap::new(),
+                    welcomes: 4,
+                    unique_requesters: 0,
+                },
+            ],
+        };
+
+        // When formatting it as text, limited to one room, and as CSV
+        let text = report.to_text(1, |room_id| {
+            (room_id == "!a:example.com").then(|| "Support".to_string())
+        });
+        let csv = report.to_csv();
+
+        // Then both should list the counts per room and topic
+        assert_eq!(
+            text,
+            "Usage since 2024-03-01: 3 help requests and 5 welcomes in 2 rooms\n\
+             - Support (!a:example.com): 3 help requests (help 2, rules 1), \
+             2 unique requesters, 1 welcomes\n\
+             ... and 1 more rooms"
+        );
+        assert_eq!(
+            csv,
+            "room_id,help_requests,welcomes,unique_requesters,topic:help,topic:rules\n\
+             !a:example.com,3,1,2,2,1\n\
+             \"!b,c:example.com\",0,4,0,0,0\n"
+        );
+        assert!(
+            report
+                .to_json()
+                .unwrap()
+                .contains("\"unique_requesters\": 2")
+        );
+    }
+
+    #[test]
+    fn test_format_date() {
+        // Given times on the epoch, a leap day and the end of a year
+        // When formatting them
+        // Then they should be the UTC dates
+        assert_eq!(format_date(0), "1970-01-01");
+        assert_eq!(format_date(951_782_400), "2000-02-29");
+        assert_eq!(format_date(1_704_067_199), "2023-12-31");
+    }
+}
@@ -0,0 +1,117 @@
+use sd_notify::NotifyState;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{debug, warn};
+
+/// Send a notification to systemd.
+///
+/// Does nothing unless the bot was started by a `Type=notify` unit, which
+/// sets `NOTIFY_SOCKET`.
+fn notify(states: &[NotifyState]) {
+    if let Err(e) = sd_notify::notify(false, states) {
+        debug!(error = %e, "Failed to notify systemd");
+    }
+}
+
+/// Tell systemd what the bot is doing, as shown by `systemctl status`.
+pub fn notify_status(status: &str) {
+    notify(&[NotifyState::Status(status)]);
+}
+
+/// Tell systemd that startup is complete.
+pub fn notify_ready(status: &str) {
+    notify(&[NotifyState::Ready, NotifyState::Status(status)]);
+}
+
+/// Tell systemd that the bot is shutting down.
+pub fn notify_stopping() {
+    notify(&[NotifyState::Stopping, NotifyState::Status("shutting down")]);
+}
+
+/// The watchdog timeout set with `WatchdogSec`, if any.
+pub fn watchdog_timeout() -> Option<Duration> {
+    let mut usec = 0;
+    sd_notify::watchdog_enabled(false, &mut usec).then(|| Duration::from_micros(usec))
+}
+
+/// Pings the systemd watchdog for as long as the sync loop makes progress.
+///
+/// A sync that hangs stops the pings, so that systemd restarts the bot. Waiting
+/// to reconnect counts as progress, since the wait is known to end.
+#[derive(Debug, Clone)]
+pub struct Watchdog {
+    timeout: Duration,
+    /// Pings stop once this passes without further progress
+    alive_until: Arc<Mutex<Instant>>,
+}
+
+impl Watchdog {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            alive_until: Arc::new(Mutex::new(Instant::now() + timeout)),
+        }
+    }
+
+    fn alive_until(&self) -> std::sync::MutexGuard<'_, Instant> {
+        self.alive_until.lock().expect("Watchdog lock poisoned")
+    }
+
+    /// Record a completed sync.
+    pub fn progress(&self) {
+        *self.alive_until() = Instant::now() + self.timeout;
+    }
+
+    /// Record that the sync loop waits for `delay` before trying again.
+    pub fn waiting(&self, delay: Duration) {
+        *self.alive_until() = Instant::now() + delay + self.timeout;
+    }
+
+    fn is_alive_at(&self, now: Instant) -> bool {
+        now < *self.alive_until()
+    }
+
+    /// Ping systemd at half the timeout, as recommended, until the task is dropped.
+    pub async fn run(self) {
+        let mut interval = tokio::time::interval(self.timeout / 2);
+        let mut stalled = false;
+        loop {
+            interval.tick().await;
+            if self.is_alive_at(Instant::now()) {
+                notify(&[NotifyState::Watchdog]);
+                stalled = false;
+            } else if !stalled {
+                warn!(
+                    timeout_seconds = self.timeout.as_secs_f64(),
+                    "Sync made no progress, no longer pinging the systemd watchdog"
+                );
+                stalled = true;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_watchdog_follows_progress() {
+        // Given a watchdog with a one minute timeout
+        let watchdog = Watchdog::new(Duration::from_secs(60));
+        let now = Instant::now();
+        let stalled = now + Duration::from_secs(90);
+
+        // When syncing, and then waiting five minutes to reconnect
+        watchdog.progress();
+        let alive_after_sync = watchdog.is_alive_at(now);
+        let stalled_after_sync = !watchdog.is_alive_at(stalled);
+        watchdog.waiting(Duration::from_secs(300));
+
+        // Then only a sync that takes longer than the timeout should stop the pings
+        assert!(alive_after_sync);
+        assert!(stalled_after_sync);
+        assert!(watchdog.is_alive_at(stalled));
+        assert!(!watchdog.is_alive_at(now + Duration::from_secs(400)));
+    }
+}
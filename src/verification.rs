@@ -0,0 +1,113 @@
+use futures_util::StreamExt;
+use matrix_sdk::encryption::verification::{
+    SasState, SasVerification, VerificationRequest, VerificationRequestState, format_emojis,
+};
+use tracing::{info, warn};
+
+/// Check whether verification requests from a user may be accepted automatically.
+pub fn is_verification_allowed(user_id: &str, admins: &[String]) -> bool {
+    admins.iter().any(|admin| admin == user_id)
+}
+
+/// Accept an incoming verification request and follow it through to the end.
+///
+/// The bot cannot compare emojis itself, so once the keys have been exchanged it
+/// logs the short authentication string and confirms it. Callers must only pass
+/// requests from users on the admin allow-list, and should run this in a span
+/// naming the sender.
+pub async fn accept_verification_request(request: VerificationRequest) {
+    info!("Accepting verification request");
+
+    if let Err(e) = request.accept().await {
+        warn!(error = %e, "Failed to accept verification request");
+        return;
+    }
+
+    let mut changes = request.changes();
+    while let Some(state) = changes.next().await {
+        match state {
+            VerificationRequestState::Transitioned { verification } => {
+                if let Some(sas) = verification.sas() {
+                    confirm_sas_verification(sas).await;
+                } else {
+                    warn!(
+                        "Verification uses an unsupported method, only emoji verification is supported"
+                    );
+                }
+                break;
+            }
+            VerificationRequestState::Done => break,
+            VerificationRequestState::Cancelled(info) => {
+                warn!(reason = info.reason(), "Verification request was cancelled");
+                break;
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Accept and confirm an emoji (SAS) verification.
+async fn confirm_sas_verification(sas: SasVerification) {
+    let device_id = sas.other_device().device_id().to_owned();
+
+    if let Err(e) = sas.accept().await {
+        warn!(error = %e, "Failed to accept emoji verification");
+        return;
+    }
+
+    let mut changes = sas.changes();
+    while let Some(state) = changes.next().await {
+        match state {
+            SasState::KeysExchanged { emojis, decimals } => {
+                match emojis {
+                    Some(emojis) => info!(
+                        %device_id,
+                        "Verifying device with emojis:\n{}",
+                        format_emojis(emojis.emojis)
+                    ),
+                    None => info!(
+                        %device_id,
+                        "Verifying device with decimals: {} {} {}",
+                        decimals.0,
+                        decimals.1,
+                        decimals.2
+                    ),
+                }
+                if let Err(e) = sas.confirm().await {
+                    warn!(%device_id, error = %e, "Failed to confirm verification");
+                    break;
+                }
+            }
+            SasState::Done { .. } => {
+                info!(%device_id, "Successfully verified device");
+                break;
+            }
+            SasState::Cancelled(info) => {
+                warn!(
+                    %device_id,
+                    reason = info.reason(),
+                    "Emoji verification was cancelled"
+                );
+                break;
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_verification_allowed() {
+        // Given an admin allow-list
+        let admins = vec!["@admin:example.com".to_string()];
+
+        // When checking admins and other users
+        // Then only listed admins should be allowed
+        assert!(is_verification_allowed("@admin:example.com", &admins));
+        assert!(!is_verification_allowed("@user:example.com", &admins));
+        assert!(!is_verification_allowed("@admin:example.com", &[]));
+    }
+}